@@ -20,6 +20,10 @@
           })) mkPoetryApplication;
           git-toprepo = mkPoetryApplication {
             projectDir = ./.;
+            nativeCheckInputs = [pkgs.git];
+            preCheck = ''
+              export GIT_TOPREPO_TEST_GIT=${pkgs.git}/bin/git
+            '';
             overrides = final: prev: {
               packaging = prev.packaging.overridePythonAttrs (old: {
                 buildInputs = (old.buildInputs or []) ++ [prev.flit-core];
@@ -4,16 +4,23 @@
 git-toprepo merges subrepositories into a common history, similar to git-subtree.
 """
 import argparse
+import hashlib
 import itertools
+import json
 import os
+import posixpath
 import re
+import shlex
 import shutil
 import subprocess
 import sys
 import textwrap
+import time
+import uuid
 from abc import ABC, abstractmethod
 from collections import defaultdict
-from dataclasses import dataclass
+from concurrent.futures import ThreadPoolExecutor
+from dataclasses import dataclass, field, replace
 from functools import cached_property, lru_cache, partial
 from pathlib import Path, PurePath, PurePosixPath
 from queue import PriorityQueue
@@ -24,7 +31,9 @@ from typing import (
     Generator,
     Iterable,
     List,
+    Mapping,
     Optional,
+    Pattern,
     Set,
     Tuple,
     TypeVar,
@@ -51,6 +60,22 @@ def patched_git_filter_repo_cleanup(repo, repack, reset, **kwargs) -> None:
 
 git_filter_repo.RepoFilter.cleanup = patched_git_filter_repo_cleanup
 
+# Ancient histories can have identities that git-filter-repo can't parse, e.g.
+# without an email. Keep the raw text as name instead of crashing and leave the
+# rest to normalize_commit_identities.
+original_git_filter_repo_parse_user = git_filter_repo.FastExportParser._parse_user
+
+
+def patched_git_filter_repo_parse_user(parser, usertype: bytes):
+    if parser._user_regexes[usertype].match(parser._currentline):
+        return original_git_filter_repo_parse_user(parser, usertype)
+    name = parser._currentline[len(usertype) + 1 : -1]
+    parser._advance_currentline()
+    return (name, None, None)
+
+
+git_filter_repo.FastExportParser._parse_user = patched_git_filter_repo_parse_user
+
 RepoName = str
 Url = str
 RawUrl = str
@@ -63,6 +88,40 @@ RepoFilterId = Union[int, CommitHash]
 
 default_fetch_args = ["--prune", "--prune-tags", "--tags"]
 
+fetch_tags_policy_args = {
+    "all": ["--prune-tags", "--tags"],
+    "none": ["--no-tags"],
+    # git-fetch follows tags pointing into the fetched history by default.
+    "reachable": [],
+}
+
+
+def with_fetch_tags_policy(fetch_args: List[str], policy: str) -> List[str]:
+    """Replaces the tag related git-fetch options according to the policy."""
+    tag_args = ("--tags", "-t", "--no-tags", "-n", "--prune-tags", "-P")
+    kept_args = [arg for arg in fetch_args if arg not in tag_args]
+    return kept_args + fetch_tags_policy_args[policy]
+
+
+def without_fetch_prune(fetch_args: List[str]) -> List[str]:
+    """Removes the pruning git-fetch options, for servers that reject them."""
+    prune_args = ("--prune", "-p", "--prune-tags", "-P")
+    return [arg for arg in fetch_args if arg not in prune_args]
+
+
+def is_fetch_prune_error(git_stderr: str) -> bool:
+    """Tells whether a failed git-fetch complained about pruning or a refspec,
+    which retrying without the pruning options might avoid."""
+    return re.search(r"prun|refspec", git_stderr, re.IGNORECASE) is not None
+
+
+max_repo_name_length = 255
+"""Common file name length limit.
+
+The repo name is used both as a directory under .git/repos/ and as a
+path component in refs/repos/<name>/.
+"""
+
 
 class Repo:
     def __init__(self, repo: Path):
@@ -145,14 +204,16 @@ class TopRepo(Repo):
     name = "top"
     name_bytes = b"top"
 
-    def __init__(self, repo: Path, fetch_url: Url, push_url: Url):
+    def __init__(self, repo: Path, fetch_url: Url, push_url: Url, prune: bool = True):
         super().__init__(repo=repo)
         self.config = RepoConfig(
             name=TopRepo.name,
             enabled=True,
             raw_urls=[],
             fetch_url=fetch_url,
-            fetch_args=default_fetch_args,
+            fetch_args=(
+                default_fetch_args if prune else without_fetch_prune(default_fetch_args)
+            ),
             push_url=push_url,
         )
 
@@ -162,6 +223,7 @@ class TopRepo(Repo):
             repo,
             fetch_url=config.top_fetch_url,
             push_url=config.top_push_url,
+            prune=config.top_prune,
         )
 
 
@@ -187,6 +249,8 @@ class PushInstruction:
     repo: Union["TopRepo", "SubRepo"]
     commit_hash: CommitHash
     extra_args: List[str]
+    mono_commit_hash: CommitHash
+    """The monorepo commit that commit_hash was split from."""
 
     def same_but_commit(self, other: "PushInstruction") -> bool:
         return self.repo.path == other.repo.path and self.extra_args == other.extra_args
@@ -205,10 +269,10 @@ def unique_extend(dest: List[_T], items: Iterable[_T]):
         unique_append(dest, item)
 
 
-def try_relative_path(path: Path, other: Path = Path.cwd()) -> Path:
-    """Returns a relative path, if possible."""
+def try_relative_path(path: Path, other: Optional[Path] = None) -> Path:
+    """Returns a relative path, if possible, by default to the current directory."""
     try:
-        return path.relative_to(other)
+        return path.relative_to(other if other is not None else Path.cwd())
     except ValueError:
         return path
 
@@ -232,6 +296,19 @@ class GitModuleInfo:
         return hash((self.name, self.path, self.branch, self.url, self.raw_url))
 
 
+def normalize_gitmodules_path(path: str) -> PurePosixPath:
+    """Normalizes sloppy submodule paths from .gitmodules to match the gitlinks.
+
+    Old commits contain e.g. `./Modules/Foo/` or Windows backslashes.
+    """
+    normalized = PurePosixPath(path.replace("\\", "/"))
+    if normalized.is_absolute():
+        normalized = normalized.relative_to("/")
+    if normalized.parts in ((), (".",)) or ".." in normalized.parts:
+        raise ValueError(f"Invalid submodule path {path!r}")
+    return normalized
+
+
 def removesuffix(text: str, suffix: str) -> str:
     # Available in Python 3.9.
     if text.endswith(suffix):
@@ -248,22 +325,67 @@ def repository_basename(repository: Url) -> str:
     return basename
 
 
-def repository_name(repository: Url) -> str:
-    name = repository
-    # Handle relative paths.
-    name = name.replace("../", "")
-    name = name.replace("./", "")
-    # Remove scheme.
-    idx = name.find("://")
+SCP_LIKE_URL_REGEX = re.compile(r"(?:[^/@]*@)?(?:\[[^\]/]*\]|[^/:\[]{2,}):")
+"""Matches the '[user@]host:' prefix of an scp-like URL.
+
+Single letter hosts are rejected to not mistake 'C:' drive letters for hosts.
+"""
+
+
+def split_url_location(url: Url) -> Tuple[str, str]:
+    """Splits an URL into its location prefix and its path.
+
+    The location contains the scheme, user, host and port, including the
+    separator to the path, so that concatenating the two gives back the URL.
+    Local paths have an empty location, or "/" if absolute. Examples:
+
+        "ssh://user@host:29418/project" -> ("ssh://user@host:29418/", "project")
+        "user@host:org/repo" -> ("user@host:", "org/repo")
+        "file:///data/repo" -> ("file:///", "data/repo")
+        "../repo" -> ("", "../repo")
+    """
+    match = re.match(r"[A-Za-z][A-Za-z0-9+.-]*::", url)
+    if match is not None:
+        # git's '<transport>::<address>' syntax.
+        location, path = split_url_location(url[match.end() :])
+        return match.group(0) + location, path
+    idx = url.find("://")
     if idx != -1:
-        name = name[idx + 3 :]
-        # Remove the domain name.
-        idx = name.find("/")
-        # idx+1 also works if no separator was found.
-        name = name[idx + 1 :]
-    # Annoying with double slash.
-    name = name.replace("//", "/")
-    name = name.strip("/")
+        slash = url.find("/", idx + 3)
+        if slash == -1:
+            return url + "/", ""
+        return url[: slash + 1], url[slash + 1 :]
+    match = SCP_LIKE_URL_REGEX.match(url)
+    if match is not None:
+        return match.group(0), url[match.end() :]
+    if url.startswith("/"):
+        return "/", url[1:]
+    return "", url
+
+
+def normalize_url_path(path: str) -> str:
+    """Removes empty, '.' and '..' segments from a slash separated path.
+
+    '..' segments that would go above the start of the path are kept.
+    """
+    segments: List[str] = []
+    for segment in path.split("/"):
+        if segment in ("", "."):
+            continue
+        if segment == ".." and len(segments) != 0 and segments[-1] != "..":
+            segments.pop()
+        else:
+            segments.append(segment)
+    return "/".join(segments)
+
+
+def repository_name(repository: Url) -> str:
+    _, path = split_url_location(repository)
+    segments = normalize_url_path(path).split("/")
+    # Relative paths going upwards say nothing about the name.
+    while len(segments) != 0 and segments[0] == "..":
+        segments.pop(0)
+    name = "/".join(segments)
     name = removesuffix(name, ".git")
     # For both an URL and a file path, assume a limited set of separators.
     for sep in r"/\:":
@@ -271,39 +393,140 @@ def repository_name(repository: Url) -> str:
     return name
 
 
+def canonical_url(url: Url) -> Url:
+    """Returns a spelling of url shared by the equivalent variants, e.g. with
+    or without a trailing `.git` or `/`."""
+    location, path = split_url_location(url)
+    path = normalize_url_path(path)
+    path = removesuffix(path, ".git")
+    return location + path
+
+
+def format_example_repo_configs(
+    raw_urls: List[RawUrl], parent_url: Url, repo_name_command: Optional[str]
+) -> List[str]:
+    """Suggests toprepo.repo.<name> configurations for unconfigured URLs.
+
+    URLs resolving to the same repository, e.g. `../Foo` and its fully
+    qualified variant, end up in the same configuration. The relative and
+    shortest spelling is preferred as fetchUrl.
+    """
+    canonical_to_urls: DefaultDict[Url, List[RawUrl]] = defaultdict(list)
+    for raw_url in raw_urls:
+        resolved_url = join_submodule_url(parent_url, raw_url)
+        canonical_to_urls[canonical_url(resolved_url)].append(raw_url)
+    name_to_groups: DefaultDict[str, List[List[RawUrl]]] = defaultdict(list)
+    for urls in canonical_to_urls.values():
+        urls.sort(key=lambda url: (not url.startswith("."), len(url), url))
+        name = suggest_repository_name(urls[0], repo_name_command)
+        name_to_groups[name].append(urls)
+    lines = []
+    for name, groups in sorted(name_to_groups.items()):
+        lines.append(f'[toprepo.repo "{name}"]')
+        for url in sorted(url for urls in groups for url in urls):
+            lines.append(f"\turls = {url}")
+        if len(groups) > 1:
+            # Different repositories that got the same name.
+            lines.append("\tfetchUrl = <fill-in>")
+        elif len(groups[0]) > 1:
+            lines.append(f"\tfetchUrl = {groups[0][0]}")
+    return lines
+
+
+def suggest_repository_name(url: RawUrl, repo_name_command: Optional[str]) -> str:
+    """Suggests a repo name for a submodule URL.
+
+    If repo_name_command is set, it is executed with the URL as the last
+    argument and the repo name is read from stdout.
+    """
+    if repo_name_command is None:
+        return repository_name(url)
+    name = subprocess.check_output(
+        shlex.split(repo_name_command) + [url],
+        text=True,
+    ).strip()
+    if name == "":
+        raise ValueError(f"toprepo.repoNameCommand printed no name for {url}")
+    return name
+
+
+default_allowed_url_schemes = ["file", "http", "https", "ssh"]
+
+default_fetch_ref_prefix = "refs/toprepo/fetch-heads/"
+gitmodules_conflict_policies = ["error", "last-wins", "first-wins", "skip-path"]
+stale_fetch_refs_seconds = 24 * 60 * 60
+
+
+def get_url_scheme(url: Url) -> Optional[str]:
+    """Returns the scheme of an URL.
+
+    Both 'scheme://' and git's '<transport>::<address>' syntax are detected.
+    Returns None for local paths and scp-like 'host:path' URLs.
+    """
+    match = re.match(r"([A-Za-z][A-Za-z0-9+.-]*)(://|::)", url)
+    if match is None:
+        return None
+    return match.group(1).lower()
+
+
+def parse_url_rewrites(config_dict: "ConfigDict") -> List[Tuple[str, Url]]:
+    """Returns (<prefix>, <base>) for `url.<base>.insteadOf=<prefix>`.
+
+    `toprepo.url.<base>.insteadOf` is also read, to share the rewrites in the
+    toprepo configuration.
+    """
+    url_rewrites = []
+    for key, values in config_dict.items():
+        for section in ("url.", "toprepo.url."):
+            if key.startswith(section) and key.endswith(".insteadof"):
+                base = key[len(section) : -len(".insteadof")]
+                url_rewrites.extend((prefix, base) for prefix in values)
+    return url_rewrites
+
+
+def rewrite_url(url: Url, url_rewrites: List[Tuple[str, Url]]) -> Url:
+    """Applies the longest matching insteadOf prefix, like git does."""
+    matches = [
+        (prefix, base) for prefix, base in url_rewrites if url.startswith(prefix)
+    ]
+    if len(matches) == 0:
+        return url
+    prefix, base = max(matches, key=lambda match: len(match[0]))
+    return base + url[len(prefix) :]
+
+
 def join_submodule_url(parent: Url, other: RawUrl) -> Url:
+    """Resolves a submodule URL relative to the URL of the parent repository.
+
+    Like git, the parent URL is treated as a directory, so "../other" next to
+    "ssh://user@host:29418/project" becomes "ssh://user@host:29418/other".
+    The user, host and port are never touched, '..' segments going above the
+    path on the host are kept in the path. Absolute URLs are returned as is.
+    """
     if other.startswith("./") or other.startswith("../") or other == ".":
-        idx = parent.find("://")
-        scheme_end = idx + 3 if idx != -1 else idx + 1
-        scheme = parent[:scheme_end]
-        parent = parent[scheme_end:]
-        parent = parent.rstrip("/")
-        while True:
-            if other.startswith("/"):
-                # Ignore double slash.
-                other = other[1:]
-            elif other.startswith("./"):
-                other = other[2:]
-            elif other.startswith("../"):
-                idx = parent.rfind("/")
-                if idx != -1:
-                    parent = parent[:idx]
-                else:
-                    # Too many '../', move it from other to parent.
-                    parent += "/.."
-                other = other[3:]
-            else:
-                break
-        if other in ("", "."):
-            ret = f"{scheme}{parent}"
-        else:
-            ret = f"{scheme}{parent}/{other}"
+        location, path = split_url_location(parent)
+        ret = location + normalize_url_path(f"{path}/{other}")
     else:
         ret = other
     return ret
 
 
+ANNOTATION_PREFIX = b"^-- "
 ANNOTATED_TOP_SUBDIR = b"<top>"
+"""Subdirectory name used for the top repository in annotations.
+
+Each expanded commit message ends with one annotation line per original
+commit, on the form
+
+    "^-- " <subdir> SP <commit-hash> LF
+
+where <subdir> is the submodule path in the monorepo, or "<top>" for the
+top repository. Trailing whitespace, e.g. CR from CRLF line endings,
+is accepted when parsing.
+
+The format is part of the generated commits and therefore also of the
+commit hashes that all users share, so it must not be configurable.
+"""
 
 
 def annotate_message(
@@ -315,10 +538,114 @@ def annotate_message(
         # Add another LF to avoid folding into the subject line
         # in 'git log --oneline'.
         ret += b"\n"
-    ret += b"^-- " + subdir + b" " + orig_commit_hash + b"\n"
+    ret += ANNOTATION_PREFIX + subdir + b" " + orig_commit_hash + b"\n"
+    return ret
+
+
+def commit_message_to_utf8(message: bytes, encoding: bytes) -> Optional[bytes]:
+    """Re-encodes a commit message from its declared encoding into UTF-8.
+
+    Returns None if the encoding is unknown or the message is not valid in it,
+    in which case the message and its encoding header are better kept as is.
+    """
+    try:
+        return message.decode(encoding.decode("ascii")).encode("utf-8")
+    except (LookupError, UnicodeDecodeError):
+        return None
+
+
+SUBMODULE_UPDATE_ENTRY_REGEX = re.compile(rb"\* Update \S+ from branch '[^']*'")
+
+
+MessageRewrite = Tuple[Pattern[bytes], bytes, int]
+"""A compiled regex, its replacement and the max number of substitutions."""
+
+
+def parse_message_rewrite(value: str) -> MessageRewrite:
+    """Parses a sed-like `s/<regex>/<replacement>/[g]` commit message rewrite.
+
+    Any character following `s` can be used as delimiter. An escaped delimiter
+    is taken literally. The replacement uses the Python syntax, e.g. `\\1` for
+    the first group.
+    """
+    if len(value) < 2 or value[0] != "s":
+        raise ValueError(f"Expected s/<regex>/<replacement>/, got {value!r}")
+    delim = value[1]
+    parts = re.split(r"(?<!\\)" + re.escape(delim), value[2:])
+    if len(parts) != 3 or parts[2] not in ("", "g"):
+        raise ValueError(f"Expected s/<regex>/<replacement>/, got {value!r}")
+    pattern, replacement = (part.replace("\\" + delim, delim) for part in parts[:2])
+    try:
+        regex = re.compile(pattern.encode("utf-8"))
+    except re.error as err:
+        raise ValueError(f"Bad regex in {value!r}: {err}") from err
+    return regex, replacement.encode("utf-8"), 0 if parts[2] == "g" else 1
+
+
+def apply_message_rewrites(message: bytes, rewrites: List[MessageRewrite]) -> bytes:
+    """Applies each rewrite to every line, like sed. Without the g flag, only
+    the first match on each line is replaced."""
+    lines = message.split(b"\n")
+    for regex, replacement, count in rewrites:
+        lines = [regex.sub(replacement, line, count=count) for line in lines]
+    return b"\n".join(lines)
+
+
+def trim_submodule_updates(message: bytes) -> bytes:
+    """Removes the submodule update summaries that Gerrit adds to superproject
+    commits, e.g. "* Update sub from branch 'main'" followed by the indented
+    sub repository messages, which are already part of the mono commit.
+    """
+
+    def is_block_line(line: bytes) -> bool:
+        return line.startswith(b"  ") or bool(
+            SUBMODULE_UPDATE_ENTRY_REGEX.fullmatch(line)
+        )
+
+    lines = message.split(b"\n")
+    kept: List[bytes] = []
+    trimmed = False
+    idx = 0
+    while idx < len(lines):
+        line = lines[idx]
+        if line == b"* submodules:" or SUBMODULE_UPDATE_ENTRY_REGEX.fullmatch(line):
+            idx += 1
+            while idx < len(lines):
+                if is_block_line(lines[idx]):
+                    idx += 1
+                elif (
+                    lines[idx] == b""
+                    and idx + 1 < len(lines)
+                    and is_block_line(lines[idx + 1])
+                ):
+                    idx += 1
+                else:
+                    break
+            while len(kept) != 0 and kept[-1] == b"":
+                kept.pop()
+            trimmed = True
+            continue
+        if trimmed and line != b"" and len(kept) != 0:
+            # Keep the paragraph separation to what follows the removed block.
+            kept.append(b"")
+        trimmed = False
+        kept.append(line)
+        idx += 1
+    ret = b"\n".join(kept)
+    if message.endswith(b"\n") and not ret.endswith(b"\n"):
+        ret += b"\n"
     return ret
 
 
+def parse_annotations(message: bytes) -> List[Tuple[bytes, CommitHash]]:
+    """Returns all (subdir, commit hash) annotations in a commit message."""
+    annotation_regex = rb"^%s(.+) ([0-9a-f]+)[ \t\r]*$" % re.escape(ANNOTATION_PREFIX)
+    return [
+        (match.group(1), match.group(2))
+        for match in re.finditer(annotation_regex, message, re.MULTILINE)
+    ]
+
+
 def join_annotated_commit_messages(messages: List[bytes]) -> bytes:
     top_messages = []
     bottom_messages = []
@@ -339,7 +666,10 @@ def try_parse_top_hash_from_message(message: bytes) -> Optional[CommitHash]:
 def try_parse_commit_hash_from_message(
     message: bytes, subdir: bytes
 ) -> Optional[CommitHash]:
-    hash_annotation_regex = rb"^\^-- %s ([0-9a-f]+)$" % subdir
+    hash_annotation_regex = rb"^%s%s ([0-9a-f]+)[ \t\r]*$" % (
+        re.escape(ANNOTATION_PREFIX),
+        re.escape(subdir),
+    )
     matches = list(re.finditer(hash_annotation_regex, message, re.MULTILINE))
     if len(matches) == 0:
         return None
@@ -368,6 +698,102 @@ def try_get_topic_from_message(message: bytes) -> Optional[str]:
     return topic
 
 
+SPLIT_GROUP_FOOTER = b"Toprepo-Split-Group"
+
+
+def try_get_split_group_from_message(message: bytes) -> Optional[str]:
+    """Returns the Toprepo-Split-Group footer, the last one if there are many."""
+    matches = re.findall(
+        rb"^%s: ([0-9a-f-]+)[ \t\r]*$" % SPLIT_GROUP_FOOTER, message, re.MULTILINE
+    )
+    if len(matches) == 0:
+        return None
+    return matches[-1].decode("utf-8")
+
+
+def add_split_group_footer(message: bytes, split_group: str) -> bytes:
+    """Appends a Toprepo-Split-Group footer to the last paragraph of footers."""
+    return add_footer(message, SPLIT_GROUP_FOOTER, split_group.encode("utf-8"))
+
+
+def add_footer(message: bytes, key: bytes, value: bytes) -> bytes:
+    """Appends a `<key>: <value>` footer to the last paragraph of footers.
+
+    A new paragraph is started if the message doesn't end with footers.
+    """
+    footer = b"%s: %s\n" % (key, value)
+    message = message.rstrip(b"\n") + b"\n"
+    paragraphs = message.split(b"\n\n")
+    last_lines = paragraphs[-1].rstrip(b"\n").split(b"\n")
+    if len(paragraphs) > 1 and all(
+        re.match(rb"^[\w-]+: ", line) for line in last_lines
+    ):
+        return message + footer
+    return message + b"\n" + footer
+
+
+TAG_SIGNATURE_PREFIXES = [
+    b"-----BEGIN PGP SIGNATURE-----",
+    b"-----BEGIN PGP MESSAGE-----",
+    b"-----BEGIN SSH SIGNATURE-----",
+    b"-----BEGIN SIGNED MESSAGE-----",
+]
+
+
+def try_get_tag_signature(tag_object: bytes) -> Optional[bytes]:
+    """Returns the signature at the end of a raw tag object, if any."""
+    for line_start in re.finditer(rb"^", tag_object, re.MULTILINE):
+        rest = tag_object[line_start.start() :]
+        if any(rest.startswith(prefix) for prefix in TAG_SIGNATURE_PREFIXES):
+            return rest
+    return None
+
+
+def append_tag_signature(
+    message: bytes, tag_hash: CommitHash, signature: bytes
+) -> bytes:
+    """Appends the signature of another tag to a tag message.
+
+    The signature is indented so that git doesn't consider the new tag signed.
+    """
+    message = message.rstrip(b"\n") + b"\n"
+    indented = textwrap.indent(signature.decode("utf-8"), "    ").encode("utf-8")
+    return (
+        message
+        + b"\nSignature of the top repository tag "
+        + tag_hash
+        + b", not valid for this tag:\n\n"
+        + indented
+    )
+
+
+def repo_change_id(mono_change_id: str, repo_name: RepoName) -> str:
+    """Derives a Change-Id for the part of a monorepo change in one repository."""
+    digest = hashlib.sha1(f"{mono_change_id} {repo_name}".encode("utf-8"))
+    return "I" + digest.hexdigest()
+
+
+def set_change_id(message: bytes, change_id: str) -> bytes:
+    """Replaces the Change-Id footers, or adds one if there is none."""
+    footer = b"Change-Id: " + change_id.encode("utf-8")
+    message, count = re.subn(
+        rb"^Change-Id: I[0-9a-f]{40}[ \t\r]*$", footer, message, flags=re.MULTILINE
+    )
+    if count == 0:
+        message = add_footer(message, b"Change-Id", change_id.encode("utf-8"))
+    return message
+
+
+def try_get_change_id_from_message(message: bytes) -> Optional[str]:
+    """Returns the Gerrit Change-Id footer, the last one if there are many."""
+    matches = re.findall(
+        r"^Change-Id: (I[0-9a-f]{40})[ \t\r]*$", message.decode("utf-8"), re.MULTILINE
+    )
+    if len(matches) == 0:
+        return None
+    return matches[-1]
+
+
 def log_run_git(
     repo: Optional[Path],
     args: List[str],
@@ -394,6 +820,52 @@ def log_run_git(
     return ret
 
 
+def log_run_git_tee_stderr(repo: Path, args: List[str]) -> str:
+    """Like log_run_git, but also returns what was written to stderr.
+
+    On failure, the captured stderr is available in the raised exception.
+    """
+    full_args = ["git", "-C", str(repo)] + args
+    print(f"\rRunning   {subprocess.list2cmdline(full_args)}", file=sys.stderr)
+    captured: List[str] = []
+    with subprocess.Popen(full_args, stderr=subprocess.PIPE) as proc:
+        assert proc.stderr is not None
+        for line in iter(proc.stderr.readline, b""):
+            text = line.decode("utf-8", errors="replace")
+            sys.stderr.write(text)
+            sys.stderr.flush()
+            captured.append(text)
+    if proc.returncode != 0:
+        raise subprocess.CalledProcessError(
+            proc.returncode, full_args, stderr="".join(captured)
+        )
+    return "".join(captured)
+
+
+def try_get_redirect_url(git_stderr: str) -> Optional[Url]:
+    """Returns the URL that git reported being redirected to, if any."""
+    matches = re.findall(r"^warning: redirecting to (\S+)$", git_stderr, re.MULTILINE)
+    if len(matches) == 0:
+        return None
+    return matches[-1]
+
+
+def get_object_types(repo: Repo, hashes: Iterable[bytes]) -> Dict[bytes, str]:
+    """Returns the object type of each hash, or "missing"."""
+    hashes = sorted(hashes)
+    if len(hashes) == 0:
+        return {}
+    # <hash> SP <type> SP <size> LF, or <hash> SP missing LF
+    output = subprocess.check_output(
+        ["git", "-C", str(repo.path), "cat-file", "--batch-check"],
+        input=b"".join(object_hash + b"\n" for object_hash in hashes),
+    ).decode("utf-8")
+    return {
+        object_hash: line.split(" ")[1]
+        for object_hash, line in zip(hashes, output.splitlines())
+    }
+
+
 def ref_exists(repo: Repo, ref: str) -> bool:
     result = subprocess.run(
         ["git", "-C", str(repo.path)]
@@ -440,6 +912,23 @@ class ConfigParsingError(RuntimeError):
     pass
 
 
+def parse_config_bool(key: str, value: str) -> bool:
+    """Parses a boolean git-config value, e.g. true, no, on or 0."""
+    parsed = {
+        "1": True,
+        "true": True,
+        "yes": True,
+        "on": True,
+        "0": False,
+        "false": False,
+        "no": False,
+        "off": False,
+    }.get(value.lower())
+    if parsed is None:
+        raise ConfigParsingError(f"Invalid {key} {value!r}, expected true or false")
+    return parsed
+
+
 @dataclass(frozen=True)
 class RepoConfig:
     name: RepoName
@@ -457,6 +946,33 @@ class RepoConfig:
     """Extra options for git-fetch."""
     push_url: Url
     """Absolute URL to git-push to."""
+    git_config: List[str] = field(default_factory=list)
+    """Extra `<key>=<value>` git-config options for git-fetch and git-push."""
+    push_options: List[str] = field(default_factory=list)
+    """Default `git push -o <option>` values, e.g. Gerrit's `wip`."""
+    create_branches: bool = True
+    """Allow `git-toprepo push --create-branch` to create branches upstream."""
+    license: Optional[str] = None
+    """License of the repository, e.g. an SPDX identifier, for notices."""
+    provenance_url: Optional[str] = None
+    """Where the repository comes from, for notices. Defaults to fetch_url."""
+    fetch_notes: bool = False
+    """Fetch refs/notes/* and translate them onto the monorepo commits."""
+    map_tags: bool = False
+    """Tag the monorepo commits as refs/tags/<name>/<tag> for the repo tags."""
+    message_rewrites: List[MessageRewrite] = field(default_factory=list)
+    """Rewrites of the commit messages when expanding into the monorepo."""
+    push_message_rewrites: List[MessageRewrite] = field(default_factory=list)
+    """Rewrites of the commit messages when pushing to the repository."""
+
+    def git_config_args(self) -> List[str]:
+        """Returns the `-c <key>=<value>` arguments for the git command line."""
+        return [arg for key_value in self.git_config for arg in ("-c", key_value)]
+
+    def with_fetch_tags(self, policy: str) -> "RepoConfig":
+        return replace(
+            self, fetch_args=with_fetch_tags_policy(self.fetch_args, policy)
+        )
 
 
 _ConfigDict_unset = "git_toprepo_ConfigDict_unset"
@@ -568,11 +1084,19 @@ class ContentConfigLoader(ConfigLoader):
     def git_config_list(self) -> str:
         config_file_content = self.read_config_file_content()
         return subprocess.check_output(
-            ["git", "config", "--file", "-", "--list"],
+            ["git", "config", "--file", "-", "--no-includes", "--list"],
             input=config_file_content,
             text=True,
         )
 
+    def get_included_loader(self, path: str) -> "ContentConfigLoader":
+        """Returns a loader for a file included from this one."""
+        raise ValueError(f"Cannot include {path!r} from {type(self).__name__}")
+
+    def get_directory_loaders(self) -> Optional[List["ContentConfigLoader"]]:
+        """Returns loaders for the *.config files if the location is a directory."""
+        return None
+
 
 class StaticContentConfigLoader(ContentConfigLoader):
     def __init__(self, content: str):
@@ -595,6 +1119,18 @@ class LocalFileConfigLoader(ContentConfigLoader):
             return ""
         return self.filename.read_text(encoding="utf-8")
 
+    def get_included_loader(self, path: str) -> ContentConfigLoader:
+        return LocalFileConfigLoader(self.filename.parent / path)
+
+    def get_directory_loaders(self) -> Optional[List[ContentConfigLoader]]:
+        if not self.filename.is_dir():
+            return None
+        return [
+            LocalFileConfigLoader(path)
+            for path in sorted(self.filename.glob("*.config"))
+            if not path.is_dir()
+        ]
+
 
 class GitRemoteConfigLoader(ContentConfigLoader):
     def __init__(
@@ -626,6 +1162,115 @@ class GitRemoteConfigLoader(ContentConfigLoader):
             text=True,
         )
 
+    def get_included_loader(self, path: str) -> ContentConfigLoader:
+        # Same ref, already fetched.
+        return GitRemoteConfigLoader(
+            url=self.url,
+            remote_ref=self.remote_ref,
+            filename=PurePosixPath(posixpath.normpath(self.filename.parent / path)),
+            local_repo=self.local_repo,
+            local_ref=self.local_ref,
+        )
+
+    def get_directory_loaders(self) -> Optional[List[ContentConfigLoader]]:
+        tree = f"{self.local_ref}:{self.filename.as_posix()}"
+        object_type = subprocess.run(
+            ["git", "-C", str(self.local_repo.path), "cat-file", "-t", tree],
+            check=False,
+            stdout=subprocess.PIPE,
+            stderr=subprocess.DEVNULL,
+            text=True,
+        ).stdout.strip()
+        if object_type != "tree":
+            return None
+        # <mode> SP <type> SP <object> TAB <name>
+        entries = subprocess.check_output(
+            ["git", "-C", str(self.local_repo.path), "ls-tree", "-z", tree],
+            text=True,
+        ).split("\0")
+        names = [
+            entry.split("\t", 1)[1]
+            for entry in entries
+            if entry.split(" ", 2)[1:2] == ["blob"] and entry.endswith(".config")
+        ]
+        return [
+            GitRemoteConfigLoader(
+                url=self.url,
+                remote_ref=self.remote_ref,
+                filename=self.filename / name,
+                local_repo=self.local_repo,
+                local_ref=self.local_ref,
+            )
+            for name in sorted(names)
+        ]
+
+
+class ExpandingConfigLoader(ConfigLoader):
+    """Resolves `include.path` and `${ENV_VAR}` in toprepo configuration files.
+
+    Included paths are relative to the including file and are inserted where
+    the include is, like git-config does for files on disk. A directory loads
+    all its *.config files in name order, as if they were included.
+    """
+
+    MAX_INCLUDE_DEPTH = 10
+
+    def __init__(
+        self, config_loader: ContentConfigLoader, environ: Mapping[str, str]
+    ):
+        self.config_loader = config_loader
+        self.environ = environ
+
+    def fetch_remote_config(self) -> None:
+        self.config_loader.fetch_remote_config()
+
+    def git_config_list(self) -> str:
+        return self._expand(self.config_loader, depth=0)
+
+    def _expand(self, config_loader: ContentConfigLoader, depth: int) -> str:
+        if depth > self.MAX_INCLUDE_DEPTH:
+            raise ValueError(
+                f"Too deep config includes, more than {self.MAX_INCLUDE_DEPTH} levels"
+            )
+        directory_loaders = config_loader.get_directory_loaders()
+        if directory_loaders is not None:
+            return "".join(
+                self._expand(loader, depth + 1) for loader in directory_loaders
+            )
+        lines = []
+        for line in config_loader.git_config_list().splitlines(keepends=False):
+            key, value = line.split("=", 1)
+            value = substitute_env_vars(value, self.environ)
+            if key == "include.path":
+                included_loader = config_loader.get_included_loader(value)
+                included = self._expand(included_loader, depth + 1)
+                lines.extend(included.splitlines(keepends=False))
+            else:
+                lines.append(f"{key}={value}")
+        return "".join(line + "\n" for line in lines)
+
+
+def substitute_env_vars(value: str, environ: Mapping[str, str]) -> str:
+    """Replaces `${NAME}` with the environment variable NAME.
+
+    Unset variables and malformed references are errors, to not silently
+    produce URLs for the wrong site. Use `$$` for a literal `$`.
+    """
+
+    def replace(match: re.Match) -> str:
+        if match.group(0) == "$$":
+            return "$"
+        name = match.group(1)
+        if name is None or not re.fullmatch(r"[A-Za-z_][A-Za-z0-9_]*", name):
+            raise ValueError(f"Invalid environment variable reference in {value!r}")
+        if name not in environ:
+            raise ValueError(
+                f"Environment variable {name} is not set, used in {value!r}"
+            )
+        return environ[name]
+
+    return re.sub(r"\$\$|\$\{([^}]*)\}|\$\{", replace, value)
+
 
 class ConfigAccumulator:
     def __init__(self, monorepo: MonoRepo, online: bool):
@@ -638,6 +1283,9 @@ class ConfigAccumulator:
         except RuntimeError as err:
             print(f"ERROR: Could not find configuration location: {err}")
             return None
+        except ValueError as err:
+            print(f"ERROR: Could not load configuration: {err}")
+            return None
 
     def load_main_config(self) -> ConfigDict:
         """Load from the remote unless specified in .git/config."""
@@ -708,14 +1356,14 @@ class ConfigAccumulator:
 
     def get_config_loader(self, name: str, config_dict: ConfigDict) -> ConfigLoader:
         loader_type = config_dict["type"][-1]
-        config_loader: Union[
-            StaticContentConfigLoader, LocalFileConfigLoader, GitRemoteConfigLoader
-        ]
+        config_loader: ConfigLoader
         if loader_type == "none":
             config_loader = StaticContentConfigLoader("")
         elif loader_type == "file":
             file_path = config_dict["path"][-1]
-            config_loader = LocalFileConfigLoader(self.monorepo.path / file_path)
+            config_loader = ExpandingConfigLoader(
+                LocalFileConfigLoader(self.monorepo.path / file_path), os.environ
+            )
         elif loader_type == "git":
             # Load.
             raw_url = config_dict["url"][-1]
@@ -726,12 +1374,15 @@ class ConfigAccumulator:
             url = join_submodule_url(parent_url, raw_url)
             filename_path = PurePosixPath(filename)
             # Create.
-            config_loader = GitRemoteConfigLoader(
-                url=url,
-                remote_ref=ref,
-                filename=filename_path,
-                local_repo=self.monorepo,
-                local_ref=f"refs/toprepo/config/{name}",
+            config_loader = ExpandingConfigLoader(
+                GitRemoteConfigLoader(
+                    url=url,
+                    remote_ref=ref,
+                    filename=filename_path,
+                    local_repo=self.monorepo,
+                    local_ref=f"refs/toprepo/config/{name}",
+                ),
+                os.environ,
             )
         else:
             raise ValueError(f"Invalid toprepo.config.type {loader_type!r}")
@@ -739,24 +1390,87 @@ class ConfigAccumulator:
 
 
 def get_gitmodules_info(
-    config_loader: ConfigLoader, parent_url: Url
+    config_loader: ConfigLoader,
+    parent_url: Url,
+    conflict_policy: str = "error",
+    context: str = "",
 ) -> List[GitModuleInfo]:
-    """Parses the output from 'git config --list --file .gitmodules'."""
+    """Parses the output from 'git config --list --file .gitmodules'.
+
+    Args:
+        conflict_policy: One of gitmodules_conflict_policies, deciding what to
+            do with duplicated submodule sections that disagree.
+        context: Where the .gitmodules file comes from, e.g. " at commit <hash>",
+            used in warnings and errors.
+    """
     submod_config_mapping = config_loader.get_config_dict().extract_mapping("submodule")
+    hint = "set toprepo.gitmodulesConflicts to recover"
+    action = {
+        "last-wins": "using the last value",
+        "first-wins": "using the first value",
+        "skip-path": "skipping the submodule",
+    }.get(conflict_policy)
 
     configs: Dict[PurePosixPath, GitModuleInfo] = {}
+    skipped_paths: Set[PurePosixPath] = set()
     for name, config_dict in submod_config_mapping.items():
-        raw_url: RawUrl = config_dict.get_singleton("url")
+        values: Dict[str, Optional[str]] = {}
+        conflicting = False
+        for key in ["url", "path", "branch"]:
+            key_values = config_dict.get(key, [])
+            if len(set(key_values)) <= 1:
+                values[key] = key_values[-1] if key_values else None
+                continue
+            if conflict_policy == "error":
+                try:
+                    config_dict.get_singleton(key)
+                except ValueError as err:
+                    raise ValueError(
+                        f"submodule.{name}: {err} in .gitmodules{context}, {hint}"
+                    )
+            warning_log.warn(
+                ("gitmodules conflict", f"submodule.{name}.{key}"),
+                f"Conflicting submodule.{name}.{key} in .gitmodules{context}, {action}",
+            )
+            conflicting = True
+            if conflict_policy == "first-wins":
+                values[key] = key_values[0]
+            else:
+                values[key] = key_values[-1]
+        raw_url = values["url"]
+        path = values["path"]
+        if raw_url is None or path is None:
+            raise ValueError(
+                f"submodule.{name} lacks url or path in .gitmodules{context}"
+            )
         resolved_url = join_submodule_url(parent_url, raw_url)
         submod_info = GitModuleInfo(
             name=name,
-            path=PurePosixPath(config_dict.get_singleton("path")),
-            branch=config_dict.get_singleton("branch", None),
+            path=normalize_gitmodules_path(path),
+            branch=values["branch"],
             url=resolved_url,
             raw_url=raw_url,
         )
-        if submod_info.path in configs:
-            raise ValueError("Duplicated submodule configs for {submod_info.path}")
+        if conflicting and conflict_policy == "skip-path":
+            skipped_paths.add(submod_info.path)
+            continue
+        if submod_info.path in configs or submod_info.path in skipped_paths:
+            if conflict_policy == "error":
+                raise ValueError(
+                    f"Duplicated submodule configs for {submod_info.path} "
+                    + f"in .gitmodules{context}, {hint}"
+                )
+            warning_log.warn(
+                ("gitmodules conflict", str(submod_info.path)),
+                f"Duplicated submodule configs for {submod_info.path} "
+                + f"in .gitmodules{context}, {action}",
+            )
+            if conflict_policy == "first-wins":
+                continue
+            if conflict_policy == "skip-path":
+                configs.pop(submod_info.path, None)
+                skipped_paths.add(submod_info.path)
+                continue
         configs[submod_info.path] = submod_info
 
     return list(configs.values())
@@ -775,6 +1489,33 @@ class Config:
 
     repos: List[RepoConfig]
 
+    top_prune: bool = True
+    """Prune when fetching the top repository, from toprepo.top.prune."""
+
+    repo_name_command: Optional[str] = None
+    """Command to suggest repo names for unknown submodule URLs."""
+
+    reencode_messages: bool = False
+    """Convert all commit messages in the monorepo to UTF-8."""
+
+    fetch_ref_prefix: str = default_fetch_ref_prefix
+    """Where to store the refs fetched by 'git-toprepo fetch <remote> <ref>'."""
+
+    quiet_branches: List[str] = field(default_factory=list)
+    """Regexes for top repository branches to not warn about .gitmodules in."""
+
+    keep_tag_signatures: bool = False
+    """Append the signatures of top repository tags to the monorepo tag messages."""
+
+    gitmodules_conflicts: str = "error"
+    """How to resolve conflicting duplicated sections in historical .gitmodules."""
+
+    trim_submodule_updates: bool = False
+    """Remove Gerrit's submodule update summaries from the commit messages."""
+
+    change_id_per_repo: bool = False
+    """Give each pushed repository its own Change-Id, derived from the mono one."""
+
     @cached_property
     def raw_url_to_repos(self) -> Dict[RawUrl, List[RepoConfig]]:
         # Map URL to RepoConfig.
@@ -784,6 +1525,20 @@ class Config:
                 raw_url_to_repos[raw_url].append(repo_config)
         return raw_url_to_repos
 
+    @cached_property
+    def message_rewrites(self) -> Dict[RepoName, List[MessageRewrite]]:
+        return {
+            repo_config.name: repo_config.message_rewrites
+            for repo_config in self.repos
+            if repo_config.message_rewrites
+        }
+
+    def with_fetch_tags(self, policy: str) -> "Config":
+        """Overrides the tag fetching policy for all sub repos."""
+        return replace(
+            self, repos=[repo.with_fetch_tags(policy) for repo in self.repos]
+        )
+
     @staticmethod
     def try_create(config_dict: ConfigDict) -> Optional["Config"]:
         try:
@@ -820,12 +1575,52 @@ class Config:
             top_push_url = config_dict.get("toprepo.top.pushurl", [None])[-1]
             if top_push_url is None:
                 raise ConfigParsingError("Config remote.top.pushUrl is not set")
+        top_prune = parse_config_bool(
+            "toprepo.top.prune",
+            config_dict.get("toprepo.top.prune", ["true"])[-1],
+        )
         repo_configs = Config.parse_repo_configs(
             repo_config_dicts,
             wanted_repos_patterns,
             parent_fetch_url=top_fetch_url,
             parent_push_url=top_push_url,
         )
+        # The sub repositories are fetched in .git/repos/<name>, where git
+        # doesn't see the insteadOf rules in the monorepo configuration.
+        url_rewrites = parse_url_rewrites(config_dict)
+        repo_configs = [
+            replace(
+                repo_config, fetch_url=rewrite_url(repo_config.fetch_url, url_rewrites)
+            )
+            for repo_config in repo_configs
+        ]
+
+        # Fail early instead of when fetching or pushing.
+        allowed_url_schemes = [
+            scheme.lower()
+            for scheme in config_dict.get(
+                "toprepo.allowedurlschemes", default_allowed_url_schemes
+            )
+        ]
+        urls_to_check = [
+            ("remote.origin.url", top_fetch_url),
+            ("remote.top.pushUrl", top_push_url),
+        ]
+        for repo_config in repo_configs:
+            urls_to_check.append(
+                (f"toprepo.repo.{repo_config.name}.fetchUrl", repo_config.fetch_url)
+            )
+            urls_to_check.append(
+                (f"toprepo.repo.{repo_config.name}.pushUrl", repo_config.push_url)
+            )
+        for key, url in urls_to_check:
+            scheme = get_url_scheme(url)
+            if scheme is not None and scheme not in allowed_url_schemes:
+                raise ConfigParsingError(
+                    f"The URL scheme {scheme!r} in {url} ({key}) is not allowed. "
+                    + f"Either set {key} to a supported URL "
+                    + "or add the scheme to toprepo.allowedUrlSchemes"
+                )
 
         # Find configured missing commits.
         missing_commits: DefaultDict[RawUrl, Set[CommitHash]] = defaultdict(set)
@@ -836,11 +1631,78 @@ class Config:
                 for raw_url in values:
                     missing_commits[raw_url].add(commit_hash)
 
+        reencode_messages = parse_config_bool(
+            "toprepo.reencodeMessages",
+            config_dict.get("toprepo.reencodemessages", ["false"])[-1],
+        )
+
+        fetch_ref_prefix = config_dict.get(
+            "toprepo.fetchrefprefix", [default_fetch_ref_prefix]
+        )[-1]
+        if not fetch_ref_prefix.endswith("/"):
+            fetch_ref_prefix += "/"
+        reserved_prefixes = [
+            "refs/heads/",
+            "refs/tags/",
+            "refs/remotes/",
+            REPOS_REF_PREFIX,
+        ]
+        if not fetch_ref_prefix.startswith("refs/") or any(
+            fetch_ref_prefix.startswith(reserved) for reserved in reserved_prefixes
+        ):
+            raise ConfigParsingError(
+                f"Invalid toprepo.fetchRefPrefix {fetch_ref_prefix!r}, expected "
+                + "refs/... outside refs/heads, refs/tags, refs/remotes and refs/repos"
+            )
+
+        quiet_branches = config_dict.get("toprepo.quietbranches", [])
+        for pattern in quiet_branches:
+            try:
+                re.compile(pattern)
+            except re.error as err:
+                raise ConfigParsingError(
+                    f"Invalid regex {pattern!r} in toprepo.quietBranches: {err}"
+                )
+
+        keep_tag_signatures = parse_config_bool(
+            "toprepo.keepTagSignatures",
+            config_dict.get("toprepo.keeptagsignatures", ["false"])[-1],
+        )
+
+        trim_submodule_updates = parse_config_bool(
+            "toprepo.trimSubmoduleUpdates",
+            config_dict.get("toprepo.trimsubmoduleupdates", ["false"])[-1],
+        )
+
+        change_id_per_repo = parse_config_bool(
+            "toprepo.changeIdPerRepo",
+            config_dict.get("toprepo.changeidperrepo", ["false"])[-1],
+        )
+
+        gitmodules_conflicts = config_dict.get(
+            "toprepo.gitmodulesconflicts", ["error"]
+        )[-1]
+        if gitmodules_conflicts not in gitmodules_conflict_policies:
+            raise ConfigParsingError(
+                f"Invalid toprepo.gitmodulesConflicts {gitmodules_conflicts!r}, "
+                + "expected "
+                + ", ".join(gitmodules_conflict_policies)
+            )
+
         return Config(
             missing_commits=missing_commits,
             top_fetch_url=top_fetch_url,
             top_push_url=top_push_url,
+            top_prune=top_prune,
             repos=repo_configs,
+            repo_name_command=config_dict.get("toprepo.reponamecommand", [None])[-1],
+            reencode_messages=reencode_messages,
+            fetch_ref_prefix=fetch_ref_prefix,
+            quiet_branches=quiet_branches,
+            keep_tag_signatures=keep_tag_signatures,
+            gitmodules_conflicts=gitmodules_conflicts,
+            trim_submodule_updates=trim_submodule_updates,
+            change_id_per_repo=change_id_per_repo,
         )
 
     @staticmethod
@@ -877,6 +1739,12 @@ class Config:
             raise ConfigParsingError(f"Invalid repo name {name}")
         if len(PurePosixPath(name).parts) != 1:
             raise ConfigParsingError(f"Subdirectories not allowed in repo name: {name}")
+        if len(name.encode("utf-8")) > max_repo_name_length:
+            raise ConfigParsingError(
+                f"The repo name {name} is longer than {max_repo_name_length} bytes "
+                + "and cannot be stored as a directory or ref path component, "
+                + f"please use a shorter name in [toprepo.repo {name!r}]"
+            )
         wanted_flag = Config.repo_is_wanted(name, wanted_repos_patterns)
         if wanted_flag is None:
             raise ConfigParsingError(
@@ -898,13 +1766,67 @@ class Config:
         raw_push_url = repo_config_dict.get("pushurl", [raw_fetch_url])[-1]
         push_url = join_submodule_url(parent_push_url, raw_push_url)
         fetch_args = repo_config_dict.get("fetchargs", default_fetch_args)
-        return RepoConfig(
-            name=name,
+        fetch_tags = repo_config_dict.get("fetchtags", [None])[-1]
+        if fetch_tags is not None:
+            if fetch_tags not in fetch_tags_policy_args:
+                raise ConfigParsingError(
+                    f"Invalid toprepo.repo.{name}.fetchTags {fetch_tags!r}, "
+                    + "expected one of "
+                    + ", ".join(fetch_tags_policy_args.keys())
+                )
+            fetch_args = with_fetch_tags_policy(fetch_args, fetch_tags)
+        prune = parse_config_bool(
+            f"toprepo.repo.{name}.prune",
+            repo_config_dict.get("prune", ["true"])[-1],
+        )
+        if not prune:
+            fetch_args = without_fetch_prune(fetch_args)
+        git_config = repo_config_dict.get("gitconfig", [])
+        for key_value in git_config:
+            if "=" not in key_value:
+                raise ConfigParsingError(
+                    f"Expected <key>=<value> in toprepo.repo.{name}.gitConfig, "
+                    + f"got {key_value!r}"
+                )
+        create_branches = parse_config_bool(
+            f"toprepo.repo.{name}.createBranches",
+            repo_config_dict.get("createbranches", ["true"])[-1],
+        )
+        fetch_notes = parse_config_bool(
+            f"toprepo.repo.{name}.fetchNotes",
+            repo_config_dict.get("fetchnotes", ["false"])[-1],
+        )
+        map_tags = parse_config_bool(
+            f"toprepo.repo.{name}.mapTags",
+            repo_config_dict.get("maptags", ["false"])[-1],
+        )
+        message_rewrites: Dict[str, List[MessageRewrite]] = {}
+        for key in ("messageRewrite", "pushMessageRewrite"):
+            try:
+                message_rewrites[key] = [
+                    parse_message_rewrite(value)
+                    for value in repo_config_dict.get(key.lower(), [])
+                ]
+            except ValueError as err:
+                raise ConfigParsingError(
+                    f"Invalid toprepo.repo.{name}.{key}: {err}"
+                ) from err
+        return RepoConfig(
+            name=name,
             enabled=wanted_flag,
             raw_urls=raw_urls,
             fetch_url=fetch_url,
             fetch_args=fetch_args,
             push_url=push_url,
+            git_config=git_config,
+            push_options=repo_config_dict.get("pushoptions", []),
+            create_branches=create_branches,
+            license=repo_config_dict.get("license", [None])[-1],
+            provenance_url=repo_config_dict.get("provenanceurl", [None])[-1],
+            fetch_notes=fetch_notes,
+            map_tags=map_tags,
+            message_rewrites=message_rewrites["messageRewrite"],
+            push_message_rewrites=message_rewrites["pushMessageRewrite"],
         )
 
     @staticmethod
@@ -928,6 +1850,10 @@ class Config:
         return wanted
 
 
+TOP_REMOTES = ("origin", ".", "")
+"""The remote names that refer to the top repository."""
+
+
 def remote_to_repo(
     remote: str, git_modules: List[GitModuleInfo], config: Config
 ) -> Optional[Tuple[RepoName, Optional[GitModuleInfo]]]:
@@ -958,9 +1884,8 @@ def remote_to_repo(
             _, url = url.split("/", 1)
             remote_to_name[url].add(entry)
 
-    remote_to_name["origin"].add((TopRepo.name, None))
-    remote_to_name["."].add((TopRepo.name, None))
-    remote_to_name[""].add((TopRepo.name, None))
+    for top_remote in TOP_REMOTES:
+        remote_to_name[top_remote].add((TopRepo.name, None))
     add_url(config.top_fetch_url, TopRepo.name, None)
     add_url(config.top_push_url, TopRepo.name, None)
 
@@ -1004,6 +1929,62 @@ def remote_to_repo(
     return (name, gitmod)
 
 
+def normalize_identity(
+    name: bytes, email: Optional[bytes], date: Optional[bytes]
+) -> Tuple[bytes, bytes, bytes, List[str]]:
+    """Makes an author or committer identity acceptable for git-fast-import.
+
+    Ancient histories can contain identities without an email or with weird
+    timestamps. The email is None if the identity line could not be parsed.
+
+    Returns:
+        The name, email and date to use, together with descriptions of the
+        problems that were fixed.
+    """
+    problems: List[str] = []
+    if email is None:
+        problems.append("missing email")
+        # Try to salvage a trailing date from the raw identity.
+        match = re.fullmatch(rb"(.*?) *(\d+ [+-]\d{4})?", name)
+        assert match
+        name, date = match.group(1), match.group(2)
+        email = b""
+    if re.search(rb"[<>\n]", name + email):
+        problems.append("angle brackets in name or email")
+        name = re.sub(rb"[<>\n]", b"", name)
+        email = re.sub(rb"[<>\n]", b"", email)
+    if date is None or not re.fullmatch(rb"\d+ [+-]\d{4}", date):
+        problems.append(f"invalid date {(date or b'').decode('utf-8', 'replace')!r}")
+        date = b"0 +0000"
+    if name == b"" and email == b"":
+        name = b"Unknown"
+    return name, email, date, problems
+
+
+def normalize_commit_identities(
+    commit: git_filter_repo.Commit, repo_name: RepoName
+) -> None:
+    """Normalizes the author and committer identities of commit in place,
+    warning about any malformed identity."""
+    for kind in ["author", "committer"]:
+        name, email, date, problems = normalize_identity(
+            getattr(commit, f"{kind}_name"),
+            getattr(commit, f"{kind}_email"),
+            getattr(commit, f"{kind}_date"),
+        )
+        if len(problems) == 0:
+            continue
+        original_id = (commit.original_id or b"").decode("utf-8")
+        warning_log.warn(
+            (f"malformed {kind} identity", repo_name),
+            f"Malformed {kind} identity in commit {original_id} of {repo_name}: "
+            + ", ".join(problems),
+        )
+        setattr(commit, f"{kind}_name", name)
+        setattr(commit, f"{kind}_email", email)
+        setattr(commit, f"{kind}_date", date)
+
+
 def clone_commit(commit: git_filter_repo.Commit) -> git_filter_repo.Commit:
     return git_filter_repo.Commit(
         commit.branch,
@@ -1016,6 +1997,7 @@ def clone_commit(commit: git_filter_repo.Commit) -> git_filter_repo.Commit:
         commit.message,
         commit.file_changes,
         commit.parents,
+        encoding=commit.encoding,
     )
 
 
@@ -1088,12 +2070,20 @@ class CommitMap:
 
     @staticmethod
     def collect_commits(
-        repo: Union[SubRepo, MonoRepo], refs: List[RefStr]
+        repo: Union[SubRepo, MonoRepo],
+        refs: List[RefStr],
+        commit_to_tree: Optional[Dict[CommitHash, TreeHash]] = None,
     ) -> "CommitMap":
-        """Loads metadata about all commits."""
+        """Loads metadata about all commits.
+
+        Args:
+            commit_to_tree: The result of collect_tree_hashes(repo), if already
+                known.
+        """
         print(f"Collecting metadata for {repo.name}...")
         ret = CommitMap()
-        commit_to_tree = ret.collect_tree_hashes(repo)
+        if commit_to_tree is None:
+            commit_to_tree = ret.collect_tree_hashes(repo)
 
         args = git_filter_repo.FilteringOptions.parse_args(
             ["--partial", "--refs", "dummy"]
@@ -1104,16 +2094,44 @@ class CommitMap:
         args.refs = refs
         repo_filter = git_filter_repo.RepoFilter(
             args,
-            commit_callback=partial(ret._collect_commit_callback, commit_to_tree),
+            commit_callback=partial(
+                ret._collect_commit_callback, repo.name, commit_to_tree
+            ),
         )
         repo_filter.set_output(DevNullOutputRepoFilter())
         repo_filter.run()
         return ret
 
+    @staticmethod
+    def collect_all_commits(
+        repos: List[SubRepo], jobs: int
+    ) -> Dict[RepoName, "CommitMap"]:
+        """Loads metadata about all commits in multiple repositories.
+
+        The tree hashes are resolved for up to `jobs` repositories in parallel,
+        while the commits are parsed one repository at a time, as
+        git-filter-repo keeps global state.
+        """
+        with ThreadPoolExecutor(max_workers=max(jobs, 1)) as executor:
+            tree_futures = [
+                executor.submit(CommitMap.collect_tree_hashes, repo) for repo in repos
+            ]
+            ret: Dict[RepoName, CommitMap] = {}
+            for repo, tree_future in zip(repos, tree_futures):
+                ret[repo.config.name] = CommitMap.collect_commits(
+                    repo, ["--all"], tree_future.result()
+                )
+        return ret
+
     def _collect_commit_callback(
-        self, commit_to_tree: Dict[CommitHash, TreeHash], commit, metadata
+        self,
+        repo_name: RepoName,
+        commit_to_tree: Dict[CommitHash, TreeHash],
+        commit,
+        metadata,
     ):
         _ = metadata
+        normalize_commit_identities(commit, repo_name)
         commit.depth = 1 + max(
             (
                 self.id_to_commit[parent_id].depth
@@ -1143,12 +2161,21 @@ class BumpInfo:
 
 
 class SubmoduleFilterHelper:
-    def __init__(self, source_repo: Repo, parent_url: Url):
+    def __init__(
+        self,
+        source_repo: Repo,
+        parent_url: Url,
+        quiet_branches: List[str],
+        gitmodules_conflicts: str = "error",
+    ):
         self.current_commit: Optional[git_filter_repo.Commit] = None
         self.commit_id_to_last_config_change: Dict[RepoFilterId, CommitHash] = {}
 
         self.repo = source_repo
         self.parent_url = parent_url
+        self.quiet_branches = quiet_branches
+        """Regexes for branches without warnings about invalid .gitmodules."""
+        self.gitmodules_conflicts = gitmodules_conflicts
 
     def commit_callback(self, commit: git_filter_repo.Commit) -> None:
         self.current_commit = commit
@@ -1178,10 +2205,30 @@ class SubmoduleFilterHelper:
         commit_hash = self.commit_id_to_last_config_change[self.current_commit.id]
         return self._load_submodule_configs(commit_hash)
 
+    @lru_cache()
+    def _has_gitmodules(self, commit_hash: CommitHash) -> bool:
+        result = subprocess.run(
+            ["git", "-C", str(self.repo.path), "cat-file", "-e"]
+            + [commit_hash.decode("utf-8") + ":.gitmodules"],
+            check=False,
+            stderr=subprocess.DEVNULL,
+        )
+        return result.returncode == 0
+
+    def _is_quiet_branch(self, branch: bytes) -> bool:
+        branch_str = branch.decode("utf-8")
+        for prefix in ["refs/heads/", "refs/remotes/origin/"]:
+            if branch_str.startswith(prefix):
+                branch_str = branch_str[len(prefix) :]
+                break
+        return any(re.fullmatch(pattern, branch_str) for pattern in self.quiet_branches)
+
     @lru_cache()
     def _load_submodule_configs(
         self, commit_hash: CommitHash
     ) -> Dict[bytes, GitModuleInfo]:
+        if not self._has_gitmodules(commit_hash):
+            return {}
         gitmodules = get_gitmodules_info(
             GitRemoteConfigLoader(
                 url="",
@@ -1191,12 +2238,20 @@ class SubmoduleFilterHelper:
                 local_ref=commit_hash.decode("utf-8"),
             ),
             self.parent_url,
+            self.gitmodules_conflicts,
+            f" at commit {commit_hash.decode('utf-8')}",
         )
         return {config.path.as_posix().encode("utf-8"): config for config in gitmodules}
 
     def get_submodules(
         self, commit: git_filter_repo.Commit
     ) -> List[Tuple[git_filter_repo.FileChange, Optional[GitModuleInfo]]]:
+        """Returns the changed gitlinks, sorted by path.
+
+        The order decides the order of the extra parents and the messages of
+        the expanded commit. git-filter-repo sorts the file changes already,
+        but git-fast-export itself lists e.g. `sub2` before `sub`.
+        """
         ret: List[Tuple[git_filter_repo.FileChange, Optional[GitModuleInfo]]] = []
         for file_change in commit.file_changes:
             submodule_mode = b"160000"
@@ -1207,23 +2262,33 @@ class SubmoduleFilterHelper:
                     submod_config = self.submodule_configs.get(file_change.filename)
                     if submod_config is not None:
                         ret.append((file_change, submod_config))
+                    elif not self._has_gitmodules(
+                        self.commit_id_to_last_config_change[commit.id]
+                    ):
+                        # E.g. an orphan branch with gitlinks as plain content.
+                        pass
+                    elif self._is_quiet_branch(commit.branch):
+                        pass
                     else:
-                        print(
-                            "\rWARNING: Invalid .gitmodules for "
-                            + file_change.filename.decode("utf-8")
-                            + " at commit "
-                            + commit.original_id.decode("utf-8")
+                        path = file_change.filename.decode("utf-8")
+                        warning_log.warn(
+                            ("invalid .gitmodules", path),
+                            f"Invalid .gitmodules for {path} at commit "
+                            + commit.original_id.decode("utf-8"),
                         )
+        ret.sort(key=lambda submod: submod[0].filename)
         return ret
 
 
 class ReferencedSubmodCommitsCollector:
-    def __init__(self, repo: TopRepo):
+    def __init__(
+        self, repo: TopRepo, quiet_branches: List[str], gitmodules_conflicts: str
+    ):
         self.referenced_commits: DefaultDict[RawUrl, Set[CommitHash]] = defaultdict(set)
         """Mapping from submodule URL to commit hashes."""
 
         self.submodule_filter_helper = SubmoduleFilterHelper(
-            repo, repo.config.fetch_url
+            repo, repo.config.fetch_url, quiet_branches, gitmodules_conflicts
         )
 
     def _commit_callback(self, commit: git_filter_repo.Commit, metadata):
@@ -1236,13 +2301,17 @@ class ReferencedSubmodCommitsCollector:
                 self.referenced_commits[raw_url].add(file_change.blob_id)
 
     @staticmethod
-    def collect(repo: TopRepo) -> Dict[str, Set[CommitHash]]:
+    def collect(
+        repo: TopRepo, quiet_branches: List[str], gitmodules_conflicts: str
+    ) -> Dict[str, Set[CommitHash]]:
         """Iterates through a repository and collects submodule commits.
 
         Returns:
             A mapping from submodule URL to commit hashes.
         """
-        collector = ReferencedSubmodCommitsCollector(repo)
+        collector = ReferencedSubmodCommitsCollector(
+            repo, quiet_branches, gitmodules_conflicts
+        )
 
         args = git_filter_repo.FilteringOptions.parse_args(
             ["--partial", "--refs", "dummy"]
@@ -1262,8 +2331,9 @@ class ReferencedSubmodCommitsCollector:
 
 
 class RepoFetcher:
-    def __init__(self, monorepo: MonoRepo):
+    def __init__(self, monorepo: MonoRepo, apply_redirects: bool = False):
         self.monorepo = monorepo
+        self.apply_redirects = apply_redirects
 
     def init_subrepo(self, repo: Repo):
         if not repo.path.exists():
@@ -1285,14 +2355,63 @@ class RepoFetcher:
         All the blobs and trees need to be accessible within the monorepo.
         This filtering will copy all the data over."""
         self.init_subrepo(repo)
+        previous_url = subprocess.run(
+            ["git", "-C", str(repo.path), "config", "remote.origin.url"],
+            check=False,
+            stdout=subprocess.PIPE,
+            text=True,
+        ).stdout.strip()
+        url_changed = previous_url not in ("", repo.config.fetch_url)
+        previous_heads: Dict[str, str] = {}
+        if url_changed:
+            print(
+                f"NOTE: The fetch URL of {repo.name} has changed "
+                + f"from {previous_url} to {repo.config.fetch_url}"
+            )
+            previous_heads = get_branch_commits(repo)
         # First fetch into the individual repository.
         if ref_args is None:
             ref_args = ["+refs/heads/*:refs/heads/*"]
+            if repo.config.fetch_notes:
+                ref_args.append("+refs/notes/*:refs/notes/*")
         # TODO: What about relative paths if fetch_url is from the disk?
-        log_run_git(
-            repo.path,
-            ["fetch"] + repo.config.fetch_args + [repo.config.fetch_url] + ref_args,
-        )
+        # Capture stderr to detect redirects, keep the progress output.
+        progress_args = ["--progress"] if sys.stderr.isatty() else []
+        fetch_args = repo.config.fetch_args
+        while True:
+            try:
+                fetch_stderr = log_run_git_tee_stderr(
+                    repo.path,
+                    repo.config.git_config_args()
+                    + ["fetch"]
+                    + progress_args
+                    + fetch_args
+                    + [repo.config.fetch_url]
+                    + ref_args,
+                )
+                break
+            except subprocess.CalledProcessError as err:
+                # Some mirrors reject pruning.
+                unpruned_fetch_args = without_fetch_prune(fetch_args)
+                if unpruned_fetch_args == fetch_args or not is_fetch_prune_error(
+                    err.stderr or ""
+                ):
+                    raise
+                prune_key = (
+                    "toprepo.top.prune"
+                    if repo.is_top
+                    else f"toprepo.repo.{repo.name}.prune"
+                )
+                print(
+                    f"WARNING: Fetching {repo.name} failed, retrying without "
+                    + f"pruning. Set {prune_key} to false to skip the pruning."
+                )
+                fetch_args = unpruned_fetch_args
+        redirect_url = try_get_redirect_url(fetch_stderr)
+        if redirect_url is not None:
+            self.handle_redirect(repo, redirect_url)
+        if url_changed:
+            self.check_url_change_continuity(repo, previous_heads)
         # For convenience, log where we fetched from.
         subprocess.check_call(
             ["git", "-C", str(repo.path)]
@@ -1306,19 +2425,270 @@ class RepoFetcher:
         # The toprepo itself can be moved by git-filter-repo,
         # but moving the content anyway because 'git-toprepo push' requires
         # the original commits.
+        self._copy_to_monorepo(repo)
+
+    def _copy_to_monorepo(self, repo: Union[TopRepo, SubRepo]):
         log_run_git(
             self.monorepo.path,
             ["fetch", "--quiet", "--no-tags", "--prune", str(repo.path.absolute())]
-            + [f"+refs/*:refs/repos/{repo.name}/*"],
+            + [f"+refs/*:{repo_ref_prefix(repo.name)}*"],
         )
 
+    def fetch_commits(self, repo: SubRepo, commit_hashes: Iterable[CommitHash]) -> bool:
+        """Fetches commits by their hash, for gitlinks no branch reaches.
+
+        Each fetched commit is kept by refs/toprepo/gitlinks/<hash>. Servers
+        that don't allow fetching unadvertised commits reject the request,
+        which is not an error. Such commits stay missing.
+
+        Returns:
+            True if any of the commits was fetched.
+        """
+        ref_args = [
+            f"+{commit_hash.decode('utf-8')}:refs/toprepo/gitlinks/"
+            + commit_hash.decode("utf-8")
+            for commit_hash in sorted(commit_hashes)
+        ]
+        if len(ref_args) == 0:
+            return False
+        self.init_subrepo(repo)
+        fetch_args = repo.config.git_config_args() + ["fetch", "--quiet", "--no-tags"]
+        result = log_run_git(
+            repo.path,
+            fetch_args + [repo.config.fetch_url] + ref_args,
+            check=False,
+        )
+        assert result is not None
+        fetched_any = result.returncode == 0
+        if not fetched_any and len(ref_args) > 1:
+            # A single unknown commit fails the whole fetch, try one by one.
+            for ref_arg in ref_args:
+                result = log_run_git(
+                    repo.path,
+                    fetch_args + [repo.config.fetch_url, ref_arg],
+                    check=False,
+                )
+                assert result is not None
+                fetched_any |= result.returncode == 0
+        if not fetched_any:
+            return False
+        self._copy_to_monorepo(repo)
+        return True
+
+    def handle_redirect(self, repo: Union[TopRepo, SubRepo], redirect_url: Url):
+        """Suggests, or applies, using the URL the server redirected to.
+
+        The old URL is kept in toprepo.repo.<name>.urls, so that old history
+        referring to it still resolves.
+        """
+        if isinstance(repo, TopRepo):
+            config_args = [["remote.origin.url", redirect_url]]
+        else:
+            config_args = [
+                [f"toprepo.repo.{repo.name}.fetchUrl", redirect_url],
+                ["--add", f"toprepo.repo.{repo.name}.urls", redirect_url],
+            ]
+        print(f"NOTE: Fetching {repo.name} was redirected to {redirect_url}")
+        if self.apply_redirects:
+            for args in config_args:
+                log_run_git(self.monorepo.path, ["config"] + args)
+        else:
+            print("To fetch from it directly, run")
+            for args in config_args:
+                print(f"  git config {subprocess.list2cmdline(args)}")
+            print("or fetch with --apply-redirects.")
+
+    @staticmethod
+    def check_url_change_continuity(repo: Repo, previous_heads: Dict[str, str]):
+        """Warns if branches were rewritten when fetching from a new URL.
+
+        A rewritten branch indicates that the new URL is not the same
+        repository as the old one.
+        """
+        rewritten_branches = []
+        for ref, commit_hash in get_branch_commits(repo).items():
+            previous_hash = previous_heads.get(ref)
+            if previous_hash is None or previous_hash == commit_hash:
+                continue
+            result = subprocess.run(
+                ["git", "-C", str(repo.path), "merge-base", "--is-ancestor"]
+                + [previous_hash, commit_hash],
+                check=False,
+            )
+            if result.returncode == 1:
+                rewritten_branches.append(ref)
+            else:
+                result.check_returncode()
+        if len(rewritten_branches) != 0:
+            print(
+                f"WARNING: The new URL of {repo.name} rewrote the history of "
+                + ", ".join(rewritten_branches)
+                + ". Is it still the same repository?"
+            )
+
+
+def get_branch_commits(repo: Repo) -> Dict[str, str]:
+    """Returns the commit hash for each refs/heads/* in a repository."""
+    output = subprocess.check_output(
+        ["git", "-C", str(repo.path), "for-each-ref"]
+        + ["--format=%(refname) %(objectname)", "refs/heads/"],
+        text=True,
+    )
+    return dict(line.split(" ", 1) for line in output.splitlines())
+
+
+class ExpansionMetrics:
+    """Keeps a rolling history of how long the top repo expansions take."""
+
+    max_entries = 100
+
+    def __init__(self, monorepo: MonoRepo):
+        self.path = monorepo.git_dir / "toprepo" / "metrics.json"
+
+    def load(self) -> List[Dict[str, Any]]:
+        if not self.path.exists():
+            return []
+        return json.loads(self.path.read_text(encoding="utf-8"))
+
+    def record(self, entry: Dict[str, Any]) -> None:
+        entries = self.load()
+        entries.append(entry)
+        entries = entries[-self.max_entries :]
+        self.path.parent.mkdir(parents=True, exist_ok=True)
+        self.path.write_text(json.dumps(entries, indent=2) + "\n", encoding="utf-8")
+
+
+class WarningLog:
+    """Rate limits warnings that repeat over a long history.
+
+    Warnings are grouped by a key, e.g. the kind, repo and path. Only the first
+    max_printed_per_key warnings in each group are printed, the rest are counted
+    and summarized by flush(). All warnings are written to the log file.
+
+    The same commits are visited both when collecting the referenced submodules
+    and when expanding, so a repeated message is only counted once.
+    """
+
+    max_printed_per_key = 10
+
+    def __init__(self, path: Optional[Path] = None):
+        self.path = path
+        """The file to list all the warnings in, if any."""
+        self.counts: Dict[Tuple[str, ...], int] = defaultdict(int)
+        self.messages: List[str] = []
+        self.seen_messages: Set[str] = set()
+
+    def warn(self, key: Tuple[str, ...], message: str) -> None:
+        if message in self.seen_messages:
+            return
+        self.seen_messages.add(message)
+        self.counts[key] += 1
+        self.messages.append(message)
+        if self.counts[key] <= self.max_printed_per_key:
+            print(f"\rWARNING: {message}")
+
+    def flush(self) -> None:
+        """Summarizes the suppressed warnings and writes the log file."""
+        for key, count in sorted(self.counts.items()):
+            suppressed = count - self.max_printed_per_key
+            if suppressed > 0:
+                print(
+                    f"WARNING: {suppressed} more {key[0]} warnings "
+                    + f"for {', '.join(key[1:]) or 'the history'} were not printed"
+                )
+        if self.path is not None and len(self.messages) != 0:
+            self.path.parent.mkdir(parents=True, exist_ok=True)
+            self.path.write_text(
+                "".join(f"WARNING: {message}\n" for message in self.messages),
+                encoding="utf-8",
+            )
+            if len(self.messages) > sum(
+                min(count, self.max_printed_per_key) for count in self.counts.values()
+            ):
+                print(f"All {len(self.messages)} warnings are listed in {self.path}")
+        self.counts.clear()
+        self.messages = []
+        self.seen_messages.clear()
+
+
+warning_log = WarningLog()
+"""The warnings of the running command, replaced by main() for each command."""
+
+
+class PendingFilters:
+    """The fetches done with --skip-filter, to expand by 'filter-pending'.
+
+    Each entry has the keys "repo", "subdir", "ref" and "description", where
+    "ref" is None if everything was fetched and all top refs need expanding.
+    """
 
-class RepoExpanderBase:
     def __init__(self, monorepo: MonoRepo):
+        self.path = monorepo.git_dir / "toprepo" / "pending-filter.json"
+
+    def load(self) -> List[Dict[str, Any]]:
+        if not self.path.exists():
+            return []
+        return json.loads(self.path.read_text(encoding="utf-8"))
+
+    def add(self, entries: List[Dict[str, Any]]) -> None:
+        entries = self.load() + entries
+        self.path.parent.mkdir(parents=True, exist_ok=True)
+        self.path.write_text(json.dumps(entries, indent=2) + "\n", encoding="utf-8")
+
+    def clear(self) -> None:
+        self.path.unlink(missing_ok=True)
+
+    def refs(self) -> Set[RefStr]:
+        return {entry["ref"] for entry in self.load() if entry["ref"] is not None}
+
+
+def mono_commit_tie_break_key(commit: git_filter_repo.Commit) -> Tuple[int, bytes]:
+    """Sort key preferring the newest commit, then the lowest original hash."""
+    timestamp = int(commit.committer_date.split(b" ", 1)[0])
+    return (-timestamp, commit.original_id or b"")
+
+
+class RepoExpanderBase:
+    def __init__(
+        self,
+        monorepo: MonoRepo,
+        reencode_messages: bool,
+        trim_submodule_updates: bool = False,
+        message_rewrites: Optional[Dict[RepoName, List[MessageRewrite]]] = None,
+    ):
         self.monorepo: MonoRepo = monorepo
+        self.reencode_messages = reencode_messages
+        self.trim_submodule_updates = trim_submodule_updates
+        self.message_rewrites = message_rewrites or {}
+        """Rewrites of the sub repository commit messages, per repository."""
+        self.commit_repo_names: Dict[CommitHash, RepoName] = {}
+        """The repository of each sub repository commit, to pick the rewrites."""
+
+    def _get_message(self, commit: git_filter_repo.Commit) -> bytes:
+        """Returns the message of a commit, cleaned and in UTF-8 if configured to."""
+        message = commit.message
+        if self.reencode_messages and commit.encoding is not None:
+            message = commit_message_to_utf8(message, commit.encoding) or message
+        if self.trim_submodule_updates:
+            message = trim_submodule_updates(message)
+        repo_name = self.commit_repo_names.get(commit.original_id)
+        rewrites = self.message_rewrites.get(repo_name) if repo_name else None
+        if rewrites:
+            message = apply_message_rewrites(message, rewrites)
+        return message
+
+    def _get_encoding(self, commit: git_filter_repo.Commit) -> Optional[bytes]:
+        """Returns the encoding header to keep, None if the message is re-encoded."""
+        if (
+            self.reencode_messages
+            and commit.encoding is not None
+            and commit_message_to_utf8(commit.message, commit.encoding) is not None
+        ):
+            return None
+        return commit.encoding
 
-    @staticmethod
     def _create_mono_commit_from_subrepo_commit(
+        self,
         fullref: bytes,
         subdir: bytes,
         subrepo_commit: git_filter_repo.Commit,
@@ -1327,8 +2697,9 @@ class RepoExpanderBase:
         new_commit = clone_commit(subrepo_commit)
         new_commit.branch = fullref
         new_commit.message = annotate_message(
-            subrepo_commit.message, subdir, subrepo_commit.original_id
+            self._get_message(subrepo_commit), subdir, subrepo_commit.original_id
         )
+        new_commit.encoding = self._get_encoding(subrepo_commit)
         new_commit.parents = [
             subrepo_id_to_converted_id[pid] for pid in subrepo_commit.parents
         ]
@@ -1340,19 +2711,233 @@ class RepoExpanderBase:
         return new_commit
 
 
+def get_subrepo_to_mono_commits(
+    monorepo: MonoRepo,
+) -> DefaultDict[CommitHash, List[CommitHash]]:
+    """Maps sub repository commits to the expanded monorepo commits annotated
+    with them, for all of refs/remotes/origin."""
+    # <hash> NUL <message> NUL
+    log_output = subprocess.check_output(
+        ["git", "-C", str(monorepo.path), "log", "-z", "--format=%H%x00%B"]
+        + ["--remotes=origin", "--"],
+    )
+    fields = log_output.split(b"\0")
+    ret: DefaultDict[CommitHash, List[CommitHash]] = defaultdict(list)
+    for mono_hash, message in zip(fields[0::2], fields[1::2]):
+        for subdir, commit_hash in parse_annotations(message):
+            if subdir != ANNOTATED_TOP_SUBDIR:
+                ret[commit_hash].append(mono_hash.strip(b"\n"))
+    return ret
+
+
+def format_notes_import(
+    notes_ref: RefStr, mono_to_notes: Dict[CommitHash, List[bytes]]
+) -> bytes:
+    """Creates git-fast-import input that replaces notes_ref by a single,
+    deterministic commit with the given notes."""
+    message = b"Notes translated from the sub repositories by git-toprepo\n"
+    chunks = [
+        f"commit {notes_ref}\n".encode("utf-8"),
+        b"committer git-toprepo <> 0 +0000\n",
+        b"data %d\n%s" % (len(message), message),
+    ]
+    for mono_hash, notes in sorted(mono_to_notes.items()):
+        content = b"\n".join(note.rstrip(b"\n") + b"\n" for note in notes)
+        chunks.append(b"N inline %s\ndata %d\n%s" % (mono_hash, len(content), content))
+    chunks.append(b"\n")
+    return b"".join(chunks)
+
+
+def translate_subrepo_notes(monorepo: MonoRepo, config: Config) -> None:
+    """Copies the notes fetched from the sub repositories with
+    toprepo.repo.<name>.fetchNotes to the corresponding monorepo commits.
+
+    refs/repos/<name>/notes/<x> of all such sub repositories are combined
+    into refs/notes/<x> in the monorepo, which is rewritten every time.
+    """
+    notes_repos = [repo for repo in config.repos if repo.enabled and repo.fetch_notes]
+    if len(notes_repos) == 0:
+        return
+    # refs/notes/<x> in the monorepo -> sub repository commit -> note blobs.
+    notes: DefaultDict[RefStr, DefaultDict[CommitHash, List[bytes]]] = defaultdict(
+        lambda: defaultdict(list)
+    )
+    for repo_config in notes_repos:
+        prefix = repo_ref(repo_config.name, "refs/notes/")
+        notes_refs = subprocess.check_output(
+            ["git", "-C", str(monorepo.path), "for-each-ref", "--format=%(refname)"]
+            + [prefix],
+            text=True,
+        ).splitlines()
+        for notes_ref in notes_refs:
+            # git-notes only reads refs/notes/, so read the notes tree directly.
+            # <mode> SP <type> SP <object> TAB <path>
+            ls_tree = subprocess.check_output(
+                ["git", "-C", str(monorepo.path), "ls-tree", "-r", "-z", notes_ref],
+            )
+            mono_notes_ref = "refs/notes/" + notes_ref[len(prefix) :]
+            for entry in ls_tree.split(b"\0"):
+                if entry == b"":
+                    continue
+                info, path = entry.split(b"\t", 1)
+                # The path is the annotated commit, possibly with fan-out slashes.
+                commit_hash = path.replace(b"/", b"")
+                notes[mono_notes_ref][commit_hash].append(info.split(b" ")[2])
+    if len(notes) == 0:
+        return
+    sub_to_mono = get_subrepo_to_mono_commits(monorepo)
+    for mono_notes_ref, sub_notes in sorted(notes.items()):
+        mono_to_notes: DefaultDict[CommitHash, List[bytes]] = defaultdict(list)
+        for commit_hash, blob_hashes in sub_notes.items():
+            for mono_hash in sub_to_mono.get(commit_hash, []):
+                for blob_hash in blob_hashes:
+                    mono_to_notes[mono_hash].append(
+                        subprocess.check_output(
+                            ["git", "-C", str(monorepo.path), "cat-file", "blob"]
+                            + [blob_hash.decode("utf-8")],
+                        )
+                    )
+        print(f"Translating {len(mono_to_notes)} notes into {mono_notes_ref}")
+        subprocess.run(
+            ["git", "-C", str(monorepo.path), "fast-import", "--quiet", "--force"],
+            input=format_notes_import(mono_notes_ref, mono_to_notes),
+            check=True,
+        )
+
+
+MAPPED_TAGS_REF_PREFIX = "refs/toprepo/mapped-tags/"
+"""Every tag refs/tags/<repo-name>/<tag> created by translate_subrepo_tags is
+also recorded as refs/toprepo/mapped-tags/<repo-name>/<tag>, to tell it apart
+from the tags of the top repository."""
+
+
+def get_mapped_tag_updates(
+    existing_tags: Dict[RefStr, str],
+    mapped_tags: Dict[RefStr, str],
+    new_tags: Dict[RefStr, str],
+) -> Tuple[str, List[RefStr]]:
+    """Returns the git-update-ref instructions that move the mapped tags to
+    new_tags, and the tags that are skipped because they are not mapped.
+
+    existing_tags are the current monorepo tags and mapped_tags the ones
+    created by an earlier mapping, both as refs/tags/<repo-name>/<tag>.
+    Tags that were moved after the mapping are neither updated nor deleted.
+    """
+    instructions = ""
+    skipped = []
+    for ref in sorted(mapped_tags.keys() - new_tags.keys()):
+        if existing_tags.get(ref) == mapped_tags[ref]:
+            instructions += f"delete {ref}\n"
+        instructions += f"delete {MAPPED_TAGS_REF_PREFIX}{ref[len('refs/tags/') :]}\n"
+    for ref, mono_hash in sorted(new_tags.items()):
+        old_hash = existing_tags.get(ref)
+        if old_hash is not None and old_hash != mapped_tags.get(ref):
+            skipped.append(ref)
+        elif old_hash != mono_hash:
+            instructions += f"update {ref} {mono_hash}\n"
+            instructions += (
+                f"update {MAPPED_TAGS_REF_PREFIX}{ref[len('refs/tags/') :]} "
+                + f"{mono_hash}\n"
+            )
+    return instructions, skipped
+
+
+def translate_subrepo_tags(monorepo: MonoRepo, config: Config) -> None:
+    """Tags the monorepo commits expanded from tagged sub repository commits,
+    for the sub repositories with toprepo.repo.<name>.mapTags.
+
+    refs/repos/<name>/tags/<tag> becomes the lightweight tag
+    refs/tags/<name>/<tag>, pointing at the oldest monorepo commit annotated
+    with the tagged commit. Mapped tags that no longer apply are removed,
+    while tags of the top repository with the same name are left alone.
+    """
+    tag_repos = [repo for repo in config.repos if repo.enabled and repo.map_tags]
+    if len(tag_repos) == 0:
+        return
+    sub_to_mono = get_subrepo_to_mono_commits(monorepo)
+    update_ref_instruction = ""
+    for repo_config in tag_repos:
+        prefix = repo_ref(repo_config.name, "refs/tags/")
+        mono_prefix = f"refs/tags/{repo_config.name}/"
+        mapped_prefix = f"{MAPPED_TAGS_REF_PREFIX}{repo_config.name}/"
+        # <refname> SP <peeled-object> SP <object>
+        for_each_ref = subprocess.check_output(
+            ["git", "-C", str(monorepo.path), "for-each-ref"]
+            + ["--format=%(refname) %(*objectname) %(objectname)"]
+            + [prefix, mono_prefix, mapped_prefix],
+            text=True,
+        ).splitlines()
+        existing_tags: Dict[RefStr, str] = {}
+        mapped_tags: Dict[RefStr, str] = {}
+        new_tags: Dict[RefStr, str] = {}
+        for line in for_each_ref:
+            ref, peeled, object_hash = line.split(" ")
+            if ref.startswith(mono_prefix):
+                existing_tags[ref] = object_hash
+                continue
+            if ref.startswith(mapped_prefix):
+                mapped_tags[mono_prefix + ref[len(mapped_prefix) :]] = object_hash
+                continue
+            mono_hashes = sub_to_mono.get((peeled or object_hash).encode("utf-8"))
+            if mono_hashes:
+                # git-log lists the newest first.
+                mono_hash = mono_hashes[-1].decode("utf-8")
+                new_tags[mono_prefix + ref[len(prefix) :]] = mono_hash
+        instructions, skipped = get_mapped_tag_updates(
+            existing_tags, mapped_tags, new_tags
+        )
+        update_ref_instruction += instructions
+        for ref in skipped:
+            warning_log.warn(
+                ("unmapped tag", repo_config.name),
+                f"Not mapping the tag {ref} of {repo_config.name}, "
+                + "the monorepo already has such a tag",
+            )
+        print(f"Mapping {len(new_tags)} tags of {repo_config.name} to {mono_prefix}")
+    if update_ref_instruction != "":
+        subprocess.run(
+            ["git", "-C", str(monorepo.path), "update-ref", "--stdin"],
+            input=update_ref_instruction,
+            text=True,
+            check=True,
+        )
+
+
 class TopRepoExpander(RepoExpanderBase):
-    def __init__(self, monorepo: MonoRepo, toprepo: TopRepo, config: Config):
-        super().__init__(monorepo=monorepo)
+    def __init__(
+        self,
+        monorepo: MonoRepo,
+        toprepo: TopRepo,
+        config: Config,
+        fetcher: Optional[RepoFetcher] = None,
+        jobs: int = 1,
+    ):
+        super().__init__(
+            monorepo=monorepo,
+            reencode_messages=config.reencode_messages,
+            trim_submodule_updates=config.trim_submodule_updates,
+            message_rewrites=config.message_rewrites,
+        )
         self.toprepo = toprepo
-        self.fetcher = RepoFetcher(self.monorepo)
+        self.fetcher = fetcher if fetcher is not None else RepoFetcher(self.monorepo)
         self.config = config
+        self.jobs = jobs
+        """How many sub repositories to load in parallel."""
 
         self.commit_map: Optional[CommitMap]
+        self.non_commit_gitlinks: Dict[CommitHash, Tuple[str, RepoName]] = {}
+        """Gitlinks that refer to blobs or trees, mapped to (type, repo name)."""
+        self.failed_hash_fetches: Set[Tuple[RawUrl, CommitHash]] = set()
+        """Commits that could not be fetched by hash, not to try again."""
         self.submodule_filter_helper = SubmoduleFilterHelper(
-            self.toprepo, config.top_fetch_url
+            self.toprepo,
+            config.top_fetch_url,
+            config.quiet_branches,
+            config.gitmodules_conflicts,
         )
 
         self.mono_id_to_commit: Dict[int, git_filter_repo.Commit] = {}
+        self.ambiguous_injection_count = 0
         # TODO: Refactor to cache per commit instead of resetting on branch change.
         self.subrepo_id_to_converted_id: Dict[RepoFilterId, RepoFilterId]
         self.last_branch = b""
@@ -1368,12 +2953,26 @@ class TopRepoExpander(RepoExpanderBase):
 
         Submodules will be fetched and filtered on demand.
         """
+        start_time = time.time()
+        phase_start = time.monotonic()
+        durations: Dict[str, float] = {}
+
+        def end_phase(name: str):
+            nonlocal phase_start
+            now = time.monotonic()
+            durations[name] = round(now - phase_start, 3)
+            phase_start = now
+
+        warning_log.path = self.monorepo.git_dir / "toprepo" / "warnings.log"
         old_toprepo_refs = set(get_remote_origin_refs(self.toprepo))
         print("Collecting referenced submodules...")
-        submod_commits = ReferencedSubmodCommitsCollector.collect(self.toprepo)
+        submod_commits = ReferencedSubmodCommitsCollector.collect(
+            self.toprepo, self.config.quiet_branches, self.config.gitmodules_conflicts
+        )
         subrepos = self._get_subrepos_given_commits(submod_commits)
         for subrepo in subrepos.values():
             self.fetcher.init_subrepo(subrepo)
+        end_phase("collect")
         commit_map = self.make_commits_available(
             sorted(subrepos.values(), key=lambda repo: repo.name),
             submod_commits,
@@ -1382,8 +2981,10 @@ class TopRepoExpander(RepoExpanderBase):
         )
         if commit_map is None:
             return False
+        end_phase("load")
         self.commit_map = commit_map
         self.mono_id_to_commit = {}
+        self.ambiguous_injection_count = 0
 
         # TODO: Filter only 1000 commits per branch.
         print("Expanding the top repo to a mono repo...")
@@ -1400,15 +3001,34 @@ class TopRepoExpander(RepoExpanderBase):
             args,
             refname_callback=self._expand_toprepo_refname_callback,
             reset_callback=self._expand_toprepo_reset_callback,
+            tag_callback=self._expand_toprepo_tag_callback,
             commit_callback=lambda *args: self._expand_toprepo_commit_callback(
                 repo_filter, *args
             ),
         )
         repo_filter.run()
+        if self.ambiguous_injection_count != 0:
+            print(
+                f"INFO: {self.ambiguous_injection_count} subrepo commits were "
+                + "referenced by multiple monorepo commits, "
+                + "the newest one was used as parent when injecting history"
+            )
+        end_phase("expand")
 
         remote_monorepo_refs = set(get_remote_origin_refs(self.monorepo))
         refs_to_remove = old_toprepo_refs - remote_monorepo_refs
         delete_refs(self.monorepo, refs_to_remove)
+        translate_subrepo_notes(self.monorepo, self.config)
+        translate_subrepo_tags(self.monorepo, self.config)
+        end_phase("update_refs")
+
+        ExpansionMetrics(self.monorepo).record(
+            {
+                "time": time.strftime("%Y-%m-%dT%H:%M:%S", time.localtime(start_time)),
+                "refs": top_refs,
+                "seconds": durations,
+            }
+        )
         return True
 
     def _get_subrepos_given_commits(
@@ -1431,17 +3051,11 @@ class TopRepoExpander(RepoExpanderBase):
                 subrepos[subrepo_config.name] = subrepo
         # Print unknown urls.
         if len(unknown_urls) != 0:
-            # Group by name.
-            name_to_urls: DefaultDict[str, List[Url]] = defaultdict(list)
-            for url in unknown_urls:
-                name_to_urls[repository_name(url)].append(url)
             print("WARNING: Some subrepo URLs are missing in the git-toprepo config")
-            for name, urls in sorted(name_to_urls.items()):
-                print(f'[toprepo.repo "{name}"]')
-                for url in sorted(urls):
-                    print(f"\turls = {url}")
-                if len(urls) > 1:
-                    print("\tfetchUrl = <fill-in>")
+            for line in format_example_repo_configs(
+                unknown_urls, self.config.top_fetch_url, self.config.repo_name_command
+            ):
+                print(line)
             print(
                 "INFO: Printed above is an example git-toprepo config "
                 + "to fix the warnings."
@@ -1465,20 +3079,26 @@ class TopRepoExpander(RepoExpanderBase):
             submod_commits: A map from a raw URL to needed commit hashes.
         """
         fetched_repos: Set[str] = set()  # subrepo.config.name
-        commit_maps = {
-            subrepo.config.name: CommitMap.collect_commits(subrepo, ["--all"])
-            for subrepo in subrepos
-        }
+        self.non_commit_gitlinks = {}
+        if allow_fetching:
+            # Notes are updated without any new commits, so always fetch them.
+            for subrepo in subrepos:
+                if subrepo.config.fetch_notes:
+                    fetched_repos.add(subrepo.config.name)
+                    self.fetcher.fetch_repo(subrepo)
+        commit_maps = CommitMap.collect_all_commits(subrepos, self.jobs)
         subrepo_map = {subrepo.config.name: subrepo for subrepo in subrepos}
         missing_commits: List[Tuple[RawUrl, CommitHash]] = []
 
+        # The commits still missing and the sub repositories for each URL.
+        unresolved: Dict[RawUrl, Tuple[Set[CommitHash], List[SubRepo]]] = {}
         for url, referenced_commits in submod_commits.items():
-            subrepos = [
+            url_subrepos = [
                 subrepo_map[subrepo_config.name]
                 for subrepo_config in self.config.raw_url_to_repos.get(url, [])
                 if subrepo_config.enabled
             ]
-            if len(subrepos) == 0:
+            if len(url_subrepos) == 0:
                 continue
 
             def get_commits_to_fetch() -> Set[CommitHash]:
@@ -1486,7 +3106,7 @@ class TopRepoExpander(RepoExpanderBase):
                 ret: Set[CommitHash] = (
                     referenced_commits - self.config.missing_commits.get(url, set())
                 )
-                for subrepo in subrepos:
+                for subrepo in url_subrepos:
                     ret.difference_update(
                         commit_maps[subrepo.config.name].hash_to_commit.keys()
                     )
@@ -1495,7 +3115,7 @@ class TopRepoExpander(RepoExpanderBase):
             commits_to_fetch = get_commits_to_fetch()
             # Fetch.
             if len(commits_to_fetch) != 0 and allow_fetching:
-                for subrepo in subrepos:
+                for subrepo in url_subrepos:
                     if subrepo.config.name not in fetched_repos:
                         fetched_repos.add(subrepo.config.name)
                         self.fetcher.fetch_repo(subrepo)
@@ -1505,6 +3125,59 @@ class TopRepoExpander(RepoExpanderBase):
                         )
                 # Recalculate.
                 commits_to_fetch = get_commits_to_fetch()
+                hashes_to_fetch = {
+                    commit_hash
+                    for commit_hash in commits_to_fetch
+                    if (url, commit_hash) not in self.failed_hash_fetches
+                }
+                if len(hashes_to_fetch) != 0:
+                    # E.g. the project switched branches and the gitlinks are
+                    # on a deleted branch. Try to fetch the exact commits.
+                    for subrepo in url_subrepos:
+                        if not self.fetcher.fetch_commits(subrepo, hashes_to_fetch):
+                            continue
+                        commit_maps[subrepo.config.name] = CommitMap.collect_commits(
+                            subrepo,
+                            ["--all"],
+                        )
+                    commits_to_fetch = get_commits_to_fetch()
+                    self.failed_hash_fetches.update(
+                        (url, commit_hash)
+                        for commit_hash in commits_to_fetch & hashes_to_fetch
+                    )
+            if len(commits_to_fetch) != 0:
+                unresolved[url] = (commits_to_fetch, url_subrepos)
+
+        # Corrupt history might have gitlinks to other kinds of objects.
+        # Look them up with one git-cat-file per repository.
+        hashes_per_repo: DefaultDict[RepoName, Set[CommitHash]] = defaultdict(set)
+        for commits_to_fetch, url_subrepos in unresolved.values():
+            for subrepo in url_subrepos:
+                hashes_per_repo[subrepo.name].update(commits_to_fetch)
+        for repo_name, hashes in sorted(hashes_per_repo.items()):
+            object_types = get_object_types(subrepo_map[repo_name], hashes)
+            for commit_hash, object_type in object_types.items():
+                if object_type not in ("commit", "missing"):
+                    self.non_commit_gitlinks[commit_hash] = (object_type, repo_name)
+
+        for url, (commits_to_fetch, url_subrepos) in unresolved.items():
+            commits_to_fetch.difference_update(self.non_commit_gitlinks.keys())
+            not_known_missing = (
+                submod_commits[url] - self.config.missing_commits.get(url, set())
+            )
+            if (
+                allow_fetching
+                and len(commits_to_fetch) != 0
+                and commits_to_fetch == not_known_missing
+            ):
+                names = ", ".join(subrepo.name for subrepo in url_subrepos)
+                print(
+                    f"WARNING: None of the {len(commits_to_fetch)} commits "
+                    + f"referenced for {url} are reachable from the branches "
+                    + f"of {names}, and fetching them by hash failed. Check that "
+                    + "toprepo.repo.<name>.fetchUrl is the right repository, "
+                    + "or list the commits under [toprepo.missing-commits]."
+                )
             # Check.
             for commit_hash in sorted(commits_to_fetch):
                 missing_commits.append((url, commit_hash))
@@ -1561,6 +3234,9 @@ class TopRepoExpander(RepoExpanderBase):
                     )
                 print("rev-" + commit_hash.decode("utf-8") + " = " + url)
 
+        for name, commit_map in commit_maps.items():
+            for commit_hash in commit_map.hash_to_commit.keys():
+                self.commit_repo_names[commit_hash] = name
         return CommitMap.join(commit_maps.values())
 
     def _expand_toprepo_refname_callback(self, ref: bytes) -> bytes:
@@ -1572,12 +3248,13 @@ class TopRepoExpander(RepoExpanderBase):
             new_ref = b"refs/remotes/origin/" + ref[11:]
         elif ref.startswith(b"refs/tags/"):
             new_ref = ref
-        elif ref == b"refs/toprepo/fetch-head":
+        elif ref.startswith(self.config.fetch_ref_prefix.encode("utf-8")):
             # Special handling.
             new_ref = ref
         else:
             assert ref.startswith(b"refs/"), ref
-            new_ref = b"refs/repos/" + TopRepo.name_bytes + ref[4:]
+            top_prefix = repo_ref_prefix(TopRepo.name).encode("utf-8")
+            new_ref = top_prefix + ref[len(b"refs/") :]
         return new_ref
 
     def _expand_toprepo_reset_callback(self, reset: git_filter_repo.Reset, metadata):
@@ -1586,6 +3263,19 @@ class TopRepoExpander(RepoExpanderBase):
         self.subrepo_id_to_converted_id = {}
         self.last_branch = b""
 
+    def _expand_toprepo_tag_callback(self, tag: git_filter_repo.Tag, metadata):
+        # git-fast-export strips the signatures, as they are invalid for the
+        # rewritten tags. Optionally keep the original signed text for reference.
+        if not self.config.keep_tag_signatures or tag.original_id is None:
+            return
+        tag_object = subprocess.check_output(
+            ["git", "-C", str(self.toprepo.path)]
+            + ["cat-file", "tag", tag.original_id.decode("utf-8")],
+        )
+        signature = try_get_tag_signature(tag_object)
+        if signature is not None:
+            tag.message = append_tag_signature(tag.message, tag.original_id, signature)
+
     def _expand_toprepo_commit_callback(
         self,
         repo_filter: git_filter_repo.RepoFilter,
@@ -1601,22 +3291,30 @@ class TopRepoExpander(RepoExpanderBase):
         # The refname callback should already have been called.
         assert not mono_commit.branch.startswith(b"refs/heads/"), mono_commit.branch
         self.submodule_filter_helper.commit_callback(mono_commit)
+        normalize_commit_identities(mono_commit, TopRepo.name)
 
         self.mono_id_to_commit[mono_commit.id] = mono_commit
         first_parent_id = mono_commit.first_parent()
         if first_parent_id is not None:
             first_parent = self.mono_id_to_commit[first_parent_id]
-            mono_commit.bumps = dict(first_parent.bumps)  # Copy
+            # Shared with the parent until a submodule is changed, which makes
+            # top commits without submodule bumps cheap to expand.
+            mono_commit.bumps = first_parent.bumps
         else:
             mono_commit.bumps = {}  # Dict[bytes, BumpInfo]
 
         commit_message_parts = [
             annotate_message(
-                mono_commit.message, ANNOTATED_TOP_SUBDIR, mono_commit.original_id
+                self._get_message(mono_commit),
+                ANNOTATED_TOP_SUBDIR,
+                mono_commit.original_id,
             )
         ]
+        mono_commit.encoding = self._get_encoding(mono_commit)
 
         submods = self.submodule_filter_helper.get_submodules(mono_commit)
+        if len(submods) != 0:
+            mono_commit.bumps = dict(mono_commit.bumps)  # Copy before updating.
         for file_change, gitmodule_config in submods:
             _ = gitmodule_config
             if file_change.type == b"M":
@@ -1626,7 +3324,7 @@ class TopRepoExpander(RepoExpanderBase):
                     file_change,
                 )
             elif file_change.type == b"D":
-                mono_commit.bumps.pop(file_change.filename)
+                mono_commit.bumps.pop(file_change.filename, None)
             elif file_change.type == b"R":
                 raise NotImplementedError("Submodule renames are not implements")
             else:
@@ -1656,7 +3354,7 @@ class TopRepoExpander(RepoExpanderBase):
             file_change.blob_id = submod_commit.tree_hash
             commit_message_parts.append(
                 annotate_message(
-                    submod_commit.message, file_change.filename, submod_hash
+                    self._get_message(submod_commit), file_change.filename, submod_hash
                 )
             )
             # Recreate the history of the submodule commit graph.
@@ -1674,6 +3372,18 @@ class TopRepoExpander(RepoExpanderBase):
         else:
             # Missing commit, leave as a submodule reference.
             mono_commit.bumps.pop(file_change.filename, None)
+            non_commit = self.non_commit_gitlinks.get(submod_hash)
+            if non_commit is not None:
+                object_type, repo_name = non_commit
+                path = file_change.filename.decode("utf-8")
+                warning_log.warn(
+                    ("non-commit submodule", repo_name, path),
+                    f"Top commit {mono_commit.original_id.decode('utf-8')} "
+                    + f"has a submodule at {path} "
+                    + f"referring to {submod_hash.decode('utf-8')}, which is a "
+                    + f"{object_type} in {repo_name}, not a commit. "
+                    + "Keeping it unexpanded.",
+                )
 
         return commit_message_parts
 
@@ -1695,7 +3405,10 @@ class TopRepoExpander(RepoExpanderBase):
         counter = itertools.count(start=0, step=1)
 
         def bump_generator(max_target_subrepo_depth: int) -> Generator:
-            mono_queue_ids: Set[int] = set()
+            # The best candidate per subrepo commit id. The queue might contain
+            # replaced candidates, which are skipped.
+            mono_queue_best: Dict[int, Tuple[int, bytes]] = {}
+            mono_queue_done: Set[int] = set()
             mono_queue: PriorityQueue = PriorityQueue()
 
             def add_possible_parent(
@@ -1713,15 +3426,30 @@ class TopRepoExpander(RepoExpanderBase):
                 # Prioritize by subrepo depth, not monorepo depth.
                 # Otherwise, we don't know when we have looked far
                 # enough as the depths are not correlated.
-                if bump.subrepo_commit.id not in mono_queue_ids:
-                    mono_queue_ids.add(bump.subrepo_commit.id)
-                    mono_queue.put(
-                        (
-                            -bump.subrepo_commit.depth,
-                            next(counter),
-                            mono_parent,
-                        )
+                subrepo_id = bump.subrepo_commit.id
+                if subrepo_id in mono_queue_done:
+                    return
+                # When multiple monorepo commits point to the same subrepo
+                # commit, select deterministically independent of the order
+                # the refs are traversed in: newest commit time first,
+                # then the lowest commit hash.
+                tie_break_key = mono_commit_tie_break_key(mono_parent)
+                best_key = mono_queue_best.get(subrepo_id)
+                if best_key is not None:
+                    if tie_break_key == best_key:
+                        return
+                    self.ambiguous_injection_count += 1
+                    if tie_break_key > best_key:
+                        return
+                mono_queue_best[subrepo_id] = tie_break_key
+                mono_queue.put(
+                    (
+                        -bump.subrepo_commit.depth,
+                        tie_break_key,
+                        next(counter),
+                        mono_parent,
                     )
+                )
 
             for pid in target_mono_commit.parents:
                 add_possible_parent(
@@ -1729,7 +3457,15 @@ class TopRepoExpander(RepoExpanderBase):
                 )
 
             while not mono_queue.empty():
-                _, _, mono_commit = mono_queue.get()
+                _, tie_break_key, _, mono_commit = mono_queue.get()
+                subrepo_id = mono_commit.bumps[subdir].subrepo_commit.id
+                if (
+                    subrepo_id in mono_queue_done
+                    or mono_queue_best[subrepo_id] != tie_break_key
+                ):
+                    # Replaced by a better candidate.
+                    continue
+                mono_queue_done.add(subrepo_id)
                 # Return the latest commit pointing to a subrepo commit.
                 # This minimizes the length of feature branches before
                 # they are merged back in the history.
@@ -1831,8 +3567,19 @@ class TopRepoExpander(RepoExpanderBase):
 
 
 class SubrepoCommitExpander(RepoExpanderBase):
-    def __init__(self, monorepo: MonoRepo):
-        super().__init__(monorepo=monorepo)
+    def __init__(
+        self,
+        monorepo: MonoRepo,
+        reencode_messages: bool,
+        trim_submodule_updates: bool = False,
+        message_rewrites: Optional[Dict[RepoName, List[MessageRewrite]]] = None,
+    ):
+        super().__init__(
+            monorepo=monorepo,
+            reencode_messages=reencode_messages,
+            trim_submodule_updates=trim_submodule_updates,
+            message_rewrites=message_rewrites,
+        )
         self.few_mono_commits = 1000
         self.few_subref_commits = 999
 
@@ -1866,6 +3613,10 @@ class SubrepoCommitExpander(RepoExpanderBase):
             )
         else:
             assert converted_commit_hash is None, converted_commit_hash
+            repo_and_ref = split_repo_ref(subrepo_ref)
+            if repo_and_ref is not None:
+                for commit in commits_to_convert:
+                    self.commit_repo_names[commit.original_id] = repo_and_ref[0]
             self._insert_commits(
                 dest_ref, subdir, commits_to_convert, subrepo_id_to_converted_id
             )
@@ -2046,19 +3797,32 @@ class PushSplitError(ValueError):
 class PushSplitter:
     error: Optional[Exception]
 
-    def __init__(self, monorepo: MonoRepo, toprepo: TopRepo, config: Config):
+    def __init__(
+        self, monorepo: MonoRepo, toprepo: TopRepo, config: Config, bump_top: bool
+    ):
         self.monorepo = monorepo
         self.toprepo = toprepo
         self.config = config
+        self.bump_top = bump_top
+        """Create top repository commits bumping submodules changed together."""
 
         self.mono_id_to_subrepo_parent_ids: Dict[int, Dict[bytes, ParentsList]] = {}
+        self.repo_name_to_subdir: Dict[RepoName, bytes] = {}
+        """The subdir that pushes to each repository comes from.
+
+        A repository mounted at multiple paths must only be edited through
+        one of them, otherwise the pushed histories would be unrelated.
+        """
         self.submodule_filter_helper = SubmoduleFilterHelper(
-            self.monorepo, config.top_push_url
+            self.monorepo,
+            config.top_push_url,
+            config.quiet_branches,
+            config.gitmodules_conflicts,
         )
 
         self.error = None
 
-    def split_commits(self, local_ref: RefStr) -> List[PushInstruction]:
+    def split_commits(self, local_refs: List[RefStr]) -> List[PushInstruction]:
         # TODO: Support altering .gitmodules inside the push.
         mono_refs = get_remote_origin_refs(self.monorepo)
         # First split inside the monorepo.
@@ -2069,7 +3833,7 @@ class PushSplitter:
             + ["--source", str(self.monorepo.path)]
             + ["--target", str(self.monorepo.path)],
         )
-        args.refs = [local_ref] + [f"^{ref}" for ref in mono_refs]
+        args.refs = local_refs + [f"^{ref}" for ref in mono_refs]
         to_push: List[PushInstruction] = []
         repo_filter = None
         repo_filter = git_filter_repo.RepoFilter(
@@ -2174,17 +3938,63 @@ class PushSplitter:
                 + "which wasn't found in the following message:\n"
                 + textwrap.indent(mono_commit.message.decode("utf-8"), "  ")
             )
+        subdir_to_repo = {
+            subdir: self._get_repo_from_subdir(subdir)
+            for subdir in file_changes_per_subdir.keys()
+        }
+        for subdir, repo in subdir_to_repo.items():
+            other_subdir = self.repo_name_to_subdir.setdefault(repo.name, subdir)
+            if other_subdir != subdir:
+                raise PushSplitError(
+                    f"The repository {repo.name} is mounted at both "
+                    + f"{other_subdir.decode('utf-8')} and {subdir.decode('utf-8')}. "
+                    + "Changes through multiple paths cannot be pushed together, "
+                    + "please push the changes for one path at a time. "
+                    + "The following commit was rejected:\n"
+                    + textwrap.indent(mono_commit.message.decode("utf-8"), "  ")
+                )
+        # Changes to multiple sub repos only make sense together with a top
+        # repository commit updating the submodule pointers.
+        missing_top_bump = len(file_changes_per_subdir) > 1 and (
+            b"" not in file_changes_per_subdir
+        )
+        if missing_top_bump and not self.bump_top:
+            subdirs_str = ", ".join(
+                subdir.decode("utf-8") for subdir in file_changes_per_subdir.keys()
+            )
+            print(
+                f"WARNING: The commit changing {subdirs_str} does not update "
+                + "the top repository. The submodules need to be bumped in the "
+                + "top repository once the changes are merged, "
+                + "or use 'git-toprepo push --bump-top'. The commit message is:\n"
+                + textwrap.indent(mono_commit.message.decode("utf-8"), "  ")
+            )
+        # Link the resulting commits so they can be found as one logical change.
+        # Derive the group from the mono commit to keep it stable over re-pushes.
+        split_count = len(file_changes_per_subdir) + int(
+            missing_top_bump and self.bump_top
+        )
+        if split_count > 1:
+            split_group = try_get_split_group_from_message(trimmed_message)
+            if split_group is None:
+                split_group = str(
+                    uuid.uuid5(uuid.NAMESPACE_OID, mono_commit.original_id.decode())
+                )
+                trimmed_message = add_split_group_footer(trimmed_message, split_group)
+        new_submod_hashes: Dict[bytes, CommitHash] = {}
         # Inject a bunch of new commits.
         for subdir, file_changes in file_changes_per_subdir.items():
+            repo = subdir_to_repo[subdir]
             new_commit = clone_commit(mono_commit)
-            new_commit.message = trimmed_message
+            new_commit.message = self._get_push_message(
+                trimmed_message, mono_commit, repo
+            )
             new_commit.file_changes = file_changes
             # Exchange parents for the subrepo.
             new_commit.parents = subrepo_parent_ids_map[subdir]
             subrepo_parent_ids_map[subdir] = [new_commit.id]
 
-            repo = self._get_repo_from_subdir(subdir)
-            new_branch = f"refs/repos/{repo.name}/toprepo/push"
+            new_branch = repo_ref(repo.name, "refs/toprepo/push")
             new_commit.branch = new_branch.encode("utf-8")
 
             # NOTE: While inserting, use a backdoor to get hold of the new commit hash.
@@ -2196,6 +4006,7 @@ class PushSplitter:
             assert isinstance(
                 new_commit_hash, bytes
             ), f"Unexpected type for commit hash: {new_commit_hash!r}."
+            new_submod_hashes[subdir] = new_commit_hash
 
             # Record the branch should be pushed.
             extra_args = []
@@ -2207,12 +4018,80 @@ class PushSplitter:
                     repo=repo,
                     commit_hash=new_commit_hash,
                     extra_args=extra_args,
+                    mono_commit_hash=mono_commit.original_id,
                 )
             )
 
+        if missing_top_bump and self.bump_top:
+            self._insert_top_bump(
+                repo_filter,
+                to_push,
+                mono_commit,
+                trimmed_message,
+                subrepo_parent_ids_map,
+                new_submod_hashes,
+                topic,
+            )
+
         # Record the new commits.
         self.mono_id_to_subrepo_parent_ids[mono_commit.id] = subrepo_parent_ids_map
 
+    def _insert_top_bump(
+        self,
+        repo_filter: git_filter_repo.RepoFilter,
+        to_push: List[PushInstruction],
+        mono_commit: git_filter_repo.Commit,
+        message: bytes,
+        subrepo_parent_ids_map: DefaultDict[bytes, ParentsList],
+        new_submod_hashes: Dict[bytes, CommitHash],
+        topic: Optional[str],
+    ):
+        """Inserts a top repository commit pointing to the new subrepo commits."""
+        new_commit = clone_commit(mono_commit)
+        new_commit.message = self._get_push_message(message, mono_commit, self.toprepo)
+        new_commit.file_changes = [
+            git_filter_repo.FileChange(b"M", subdir, submod_hash, b"160000")
+            for subdir, submod_hash in sorted(new_submod_hashes.items())
+        ]
+        new_commit.parents = subrepo_parent_ids_map[b""]
+        subrepo_parent_ids_map[b""] = [new_commit.id]
+        new_branch = repo_ref(self.toprepo.name, "refs/toprepo/push")
+        new_commit.branch = new_branch.encode("utf-8")
+        new_commit.original_id = b"push-%d" % new_commit.id
+        repo_filter.insert(new_commit, direct_insertion=True)
+        repo_filter._record_remapping(new_commit, orig_parents=new_commit.parents)
+        new_commit_hash: bytes = repo_filter._get_rename(new_commit.original_id)
+        extra_args = []
+        if topic is not None:
+            extra_args.extend(["-o", f"topic={topic}"])
+        to_push.append(
+            PushInstruction(
+                repo=self.toprepo,
+                commit_hash=new_commit_hash,
+                extra_args=extra_args,
+                mono_commit_hash=mono_commit.original_id,
+            )
+        )
+
+    def _get_push_message(
+        self,
+        message: bytes,
+        mono_commit: git_filter_repo.Commit,
+        repo: Union[SubRepo, TopRepo],
+    ) -> bytes:
+        """Adapts the trimmed mono commit message to the repository pushed to."""
+        if isinstance(repo, SubRepo):
+            message = apply_message_rewrites(
+                message, repo.config.push_message_rewrites
+            )
+        if self.config.change_id_per_repo:
+            # Deterministic, so that pushing again updates the same changes.
+            mono_change_id = try_get_change_id_from_message(mono_commit.message)
+            if mono_change_id is None:
+                mono_change_id = mono_commit.original_id.decode("utf-8")
+            message = set_change_id(message, repo_change_id(mono_change_id, repo.name))
+        return message
+
     def _get_repo_from_subdir(self, subdir: bytes) -> Union[SubRepo, TopRepo]:
         if subdir == b"":
             repo: Union[SubRepo, TopRepo] = self.toprepo
@@ -2263,11 +4142,11 @@ class PushSplitter:
     def _trim_push_commit_message(mono_message: bytes) -> bytes:
         # Avoid pushing cherry-picked commits with ^-- references.
         trimmed_message = mono_message
-        idx = trimmed_message.rfind(b"\n^-- ")
+        idx = trimmed_message.rfind(b"\n" + ANNOTATION_PREFIX)
         if idx != -1:
             # Try to remove a single trailing ^-- line from an upstream cherry-pick.
             trimmed_message = trimmed_message[: idx + 1]  # Include LF
-        if b"\n^-- " in trimmed_message:
+        if b"\n" + ANNOTATION_PREFIX in trimmed_message:
             raise PushSplitError(
                 "'^-- ' was found in the following commit message. "
                 + "It looks like a commit that already exists upstream.\n"
@@ -2344,11 +4223,41 @@ def main_config(args) -> int:
         for key, values in sorted(config_dict.items()):
             for value in values:
                 print(f"{key}={value}")
+    elif args.enable is not None or args.disable is not None:
+        name = args.enable if args.enable is not None else args.disable
+        return set_repo_wanted(monorepo, config_dict, name, args.enable is not None)
     else:
         assert False, "Bad args {args}"
     return 0
 
 
+def set_repo_wanted(
+    monorepo: MonoRepo, config_dict: ConfigDict, name: RepoName, wanted: bool
+) -> int:
+    """Enables or disables a repository for the current role in .git/config."""
+    config = Config.try_create(config_dict)
+    if config is None:
+        return 1
+    repo_config = next((repo for repo in config.repos if repo.name == name), None)
+    if repo_config is None:
+        print(f"ERROR: The repository {name} is not configured")
+        return 1
+    if repo_config.enabled == wanted:
+        print(f"{name} is already {'enabled' if wanted else 'disabled'}")
+        return 0
+    role = config_dict.get("toprepo.role", ["default"])[-1]
+    key = f"toprepo.role.{role}.repos"
+    patterns = []
+    if role == "default" and config_dict[key] == ["+.*"]:
+        # Config.create() only adds the implicit +.* when the key is unset.
+        patterns.append("+.*")
+    patterns.append(("+" if wanted else "-") + re.escape(name))
+    for pattern in patterns:
+        log_run_git(monorepo.path, ["config", "--local", "--add", key, pattern])
+    print(f"Run 'git toprepo refilter' to update the history of {name}")
+    return 0
+
+
 def main_refilter(args) -> int:
     monorepo = MonoRepo(args.cwd)
     config_dict = ConfigAccumulator(monorepo, args.online).try_load_main_config()
@@ -2359,7 +4268,7 @@ def main_refilter(args) -> int:
         return 1
     toprepo = TopRepo.from_config(monorepo.get_toprepo_dir(), config)
 
-    expander = TopRepoExpander(monorepo, toprepo, config)
+    expander = TopRepoExpander(monorepo, toprepo, config, jobs=args.jobs)
     if args.from_scratch:
         # Remove all translated monorepo refs.
         top_fetch_head = "refs/toprepo/fetch-head"
@@ -2376,149 +4285,2082 @@ def main_refilter(args) -> int:
     return 0
 
 
-def main_fetch(args) -> int:
+def get_submodule_containing(
+    git_modules: List[GitModuleInfo], path: PurePosixPath
+) -> Optional[GitModuleInfo]:
+    """Returns the innermost submodule that path is inside of, if any."""
+    found: Optional[GitModuleInfo] = None
+    for mod in git_modules:
+        if path == mod.path or mod.path in path.parents:
+            if found is None or found.path in mod.path.parents:
+                found = mod
+    return found
+
+
+def get_cwd_remote(
+    monorepo_cwd: Path, git_modules: List[GitModuleInfo], config: Config
+) -> Optional[str]:
+    """Returns the submodule path when the working directory is inside an
+    expanded sub repository, otherwise None."""
+    prefix = subprocess.check_output(
+        ["git", "-C", str(monorepo_cwd), "rev-parse", "--show-prefix"],
+        text=True,
+    ).rstrip("\n")
+    if prefix == "":
+        return None
+    mod = get_submodule_containing(git_modules, PurePosixPath(prefix))
+    if mod is None or mod.raw_url not in config.raw_url_to_repos:
+        return None
+    return str(mod.path)
+
+
+def find_mono_commit_for_top(
+    monorepo: MonoRepo, mono_rev: str, top_hash: str
+) -> Optional[str]:
+    """Returns the newest ancestor of mono_rev that was expanded from top_hash."""
+    annotation = (ANNOTATION_PREFIX + ANNOTATED_TOP_SUBDIR).decode("utf-8")
+    mono_hash = subprocess.check_output(
+        ["git", "-C", str(monorepo.path), "log", "-1", "--format=%H", "-F"]
+        + [f"--grep={annotation} {top_hash}", mono_rev, "--"],
+        text=True,
+    ).strip()
+    return mono_hash or None
+
+
+def main_expand_range(args) -> int:
+    monorepo = MonoRepo(args.cwd)
+    config_dict = ConfigAccumulator(monorepo, args.online).try_load_main_config()
+    if config_dict is None:
+        return 1
+    config = Config.try_create(config_dict)
+    if config is None:
+        return 1
+    toprepo = TopRepo.from_config(monorepo.get_toprepo_dir(), config)
+
+    if ".." not in args.range:
+        print(f"ERROR: Expected <old-top>..<new-top>, got {args.range!r}")
+        return 1
+    old_rev, new_rev = args.range.split("..", 1)
+    top_hashes: List[str] = []
+    for rev in [old_rev, new_rev]:
+        result = subprocess.run(
+            ["git", "-C", str(toprepo.path), "rev-parse", "--verify", "--quiet"]
+            + [f"{rev}^{{commit}}"],
+            check=False,
+            stdout=subprocess.PIPE,
+            text=True,
+        )
+        if result.returncode != 0:
+            print(f"ERROR: {rev} is not in the top repository, fetch it first")
+            return 1
+        top_hashes.append(result.stdout.strip())
+    old_top, new_top = top_hashes
+    is_ancestor = subprocess.run(
+        ["git", "-C", str(toprepo.path), "merge-base", "--is-ancestor"]
+        + [old_top, new_top],
+        check=False,
+    )
+    if is_ancestor.returncode != 0:
+        print(f"ERROR: {old_rev} is not an ancestor of {new_rev}")
+        return 1
+
+    # A ref keeps the expanded range reachable, like for a fetched ref.
+    delete_stale_fetch_refs(monorepo, config.fetch_ref_prefix, time.time())
+    range_ref = f"{config.fetch_ref_prefix}{int(time.time())}-{os.getpid()}/range"
+    subprocess.check_call(
+        ["git", "-C", str(toprepo.path), "update-ref", range_ref, new_top]
+    )
+    expander = TopRepoExpander(monorepo, toprepo, config, jobs=args.jobs)
+    if not expander.expand_toprepo(
+        top_refs=[range_ref, "--all"],
+        allow_fetching=args.online,
+        abort_on_missing=args.abort_on_missing,
+    ):
+        return 1
+
+    old_mono = find_mono_commit_for_top(monorepo, range_ref, old_top)
+    new_mono = find_mono_commit_for_top(monorepo, range_ref, new_top)
+    if old_mono is None or new_mono is None:
+        print(f"ERROR: Could not find the expanded commits of {args.range}")
+        return 1
+    print(f"Expanded {old_top}..{new_top} into {old_mono}..{new_mono}")
+    print(f"Bisect with: git bisect start {new_mono} {old_mono}")
+    return 0
+
+
+def resolve_fetch_remote(
+    monorepo: MonoRepo,
+    toprepo: TopRepo,
+    config: Config,
+    git_modules: List[GitModuleInfo],
+    remote: str,
+) -> Optional[Tuple[Union[TopRepo, SubRepo], bytes]]:
+    """Resolves what repository to fetch from.
+
+    Returns:
+        The repository and its subdirectory in the monorepo,
+        where the subdirectory is empty for the top repository.
+    """
+    maybe = remote_to_repo(remote, git_modules, config)
+    if maybe is None:
+        return None
+    remote_name, git_module = maybe
+    if remote_name == TopRepo.name:
+        return toprepo, b""
+    assert git_module, f"git module information is required for remote: {remote_name}"
+    for subrepo_config in config.repos:
+        if subrepo_config.name == remote_name:
+            subrepo = SubRepo(
+                subrepo_config,
+                monorepo.get_subrepo_dir(subrepo_config.name),
+            )
+            return subrepo, git_module.path.as_posix().encode("utf-8")
+    print(f"ERROR: Could not resolve the remote {remote}")
+    return None
+
+
+def main_fetch(args) -> int:
+    if args.watch:
+        if args.stdin or args.ref is not None or args.remote not in TOP_REMOTES:
+            print("ERROR: --watch only fetches all refs of the top repository")
+            return 1
+        return watch_fetch(args)
+    return fetch_once(args)
+
+
+def watch_fetch(args) -> int:
+    """Fetches and expands again whenever the top repository refs change."""
+    monorepo = MonoRepo(args.cwd)
+    last_ls_remote: Optional[str] = None
+    try:
+        while True:
+            result = subprocess.run(
+                ["git", "-C", str(monorepo.path), "ls-remote", "--refs"]
+                + [monorepo.get_toprepo_fetch_url()],
+                check=False,
+                stdout=subprocess.PIPE,
+                text=True,
+            )
+            if result.returncode != 0:
+                print("WARNING: Failed to list the top repository refs")
+            elif result.stdout != last_ls_remote:
+                if fetch_once(args) == 0:
+                    last_ls_remote = result.stdout
+                else:
+                    print("WARNING: Fetching failed, trying again later")
+            time.sleep(args.interval)
+    except KeyboardInterrupt:
+        return 0
+
+
+def fetch_once(args) -> int:
+    ret = fetch_and_expand(args)
+    if ret == 0 and args.recurse_submodules:
+        if not update_kept_submodules(MonoRepo(args.cwd)):
+            return 1
+    return ret
+
+
+def update_kept_submodules(monorepo: MonoRepo) -> bool:
+    """Checks out the submodules that are not expanded in the monorepo.
+
+    Submodules of repositories not selected by the role are kept as gitlinks
+    in the monorepo history. Runs 'git submodule update --init' for those paths only.
+    """
+    ls_files = subprocess.check_output(
+        ["git", "-C", str(monorepo.path), "ls-files", "--stage", "-z"],
+    )
+    kept_paths: List[str] = []
+    for entry in ls_files.split(b"\0"):
+        if entry.startswith(b"160000 "):
+            _, path = entry.split(b"\t", 1)
+            kept_paths.append(path.decode("utf-8"))
+    if len(kept_paths) == 0:
+        return True
+    result = subprocess.run(
+        ["git", "-C", str(monorepo.path), "submodule", "update", "--init", "--"]
+        + kept_paths,
+        check=False,
+    )
+    if result.returncode != 0:
+        print("ERROR: Failed to update the submodules kept in the monorepo")
+        return False
+    return True
+
+
+def fetch_and_expand(args) -> int:
+    monorepo = MonoRepo(args.cwd)
+    config_dict = ConfigAccumulator(monorepo, online=True).try_load_main_config()
+    if config_dict is None:
+        return 1
+    config = Config.try_create(config_dict)
+    if config is None:
+        return 1
+    if not args.fetch_tags:
+        config = config.with_fetch_tags("none")
+    toprepo = TopRepo.from_config(monorepo.get_toprepo_dir(), config)
+    if not args.fetch_tags:
+        toprepo.config = toprepo.config.with_fetch_tags("none")
+    repo_fetcher = RepoFetcher(monorepo, apply_redirects=args.apply_redirects)
+
+    git_modules = get_gitmodules_info(
+        LocalFileConfigLoader(monorepo.path / ".gitmodules", allow_missing=True),
+        monorepo.get_toprepo_fetch_url(),
+    )
+    topexpander = TopRepoExpander(
+        monorepo, toprepo, config, repo_fetcher, jobs=args.jobs
+    )
+    subexpander = SubrepoCommitExpander(
+        monorepo,
+        config.reencode_messages,
+        config.trim_submodule_updates,
+        config.message_rewrites,
+    )
+
+    delete_stale_fetch_refs(monorepo, config.fetch_ref_prefix, time.time())
+    # Avoid collisions with concurrent invocations.
+    fetch_ref_namespace = f"{config.fetch_ref_prefix}{int(time.time())}-{os.getpid()}/"
+
+    if args.stdin:
+        if args.ref is not None:
+            print("ERROR: A ref cannot be combined with --stdin")
+            return 1
+        return fetch_stdin_refs(
+            args,
+            monorepo,
+            toprepo,
+            config,
+            git_modules,
+            topexpander,
+            subexpander,
+            fetch_ref_namespace,
+        )
+
+    remote: str = args.remote
+    if remote == "origin" and args.ref is not None:
+        # Fetching a ref from within a sub repository means that sub repository.
+        cwd_remote = get_cwd_remote(args.cwd, git_modules, config)
+        if cwd_remote is not None:
+            print(f"Fetching from {cwd_remote}, containing the current directory")
+            remote = cwd_remote
+    maybe = resolve_fetch_remote(monorepo, toprepo, config, git_modules, remote)
+    if maybe is None:
+        return 1
+    repo_to_fetch, subdir = maybe
+
+    ref_args: List[str]
+    if args.ref is None:
+        # Just fetch everything in that repo and do standard filtering.
+        repo_fetcher.fetch_repo(repo_to_fetch)
+        if args.do_filter:
+            if not topexpander.expand_toprepo(
+                top_refs=["--all"],
+                allow_fetching=True,
+                abort_on_missing=args.abort_on_missing,
+            ):
+                return 1
+        else:
+            PendingFilters(monorepo).add(
+                [
+                    {
+                        "repo": repo_to_fetch.name,
+                        "subdir": "",
+                        "ref": None,
+                        "description": None,
+                    }
+                ]
+            )
+            print("Skipped expanding the toprepo into the monorepo.")
+    elif "*" in args.ref or ":" in args.ref:
+        return fetch_refs(
+            args,
+            monorepo,
+            toprepo,
+            config,
+            git_modules,
+            topexpander,
+            subexpander,
+            fetch_ref_namespace,
+            [(remote, args.ref)],
+        )
+    else:
+        # Fetch ref to a temporary ref instead of FETCH_HEAD.
+        # Then there is no need for extra args to git-fetch or git-filter-repo
+        # to pick up FETCH_HEAD.
+        mono_fetch_head_ref = f"{fetch_ref_namespace}head"
+        ref_args = [f"+{args.ref}:{mono_fetch_head_ref}"]
+        repo_fetcher.fetch_repo(repo_to_fetch, ref_args)
+        if args.do_filter:
+            if repo_to_fetch.is_top:
+                # Special handling of the fetch ref prefix in the refname_callback.
+                top_fetch_head_ref = mono_fetch_head_ref
+                # TODO: Only expand top_fetch_head_ref, i.e. remove "--all".
+                # Currently, omitting --all gives different result.
+                if not topexpander.expand_toprepo(
+                    top_refs=[top_fetch_head_ref, "--all"],
+                    allow_fetching=True,
+                    abort_on_missing=args.abort_on_missing,
+                ):
+                    return 1
+            else:
+                subrepo_ref = repo_ref(repo_to_fetch.name, mono_fetch_head_ref)
+                if not subexpander.expand_subrepo_refs(
+                    subdir, subrepo_ref, dest_ref=mono_fetch_head_ref
+                ):
+                    return 1
+            update_fetch_head(
+                monorepo, [(mono_fetch_head_ref, f"{args.ref} of {remote}")]
+            )
+            print("Updated FETCH_HEAD")
+        else:
+            PendingFilters(monorepo).add(
+                [
+                    {
+                        "repo": repo_to_fetch.name,
+                        "subdir": subdir.decode("utf-8"),
+                        "ref": mono_fetch_head_ref,
+                        "description": f"{args.ref} of {remote}",
+                    }
+                ]
+            )
+            print(
+                f"Fetched {mono_fetch_head_ref} but skipped creating a "
+                + "monorepo FETCH_HEAD."
+            )
+    return 0
+
+
+def parse_fetch_refspec(refspec: str) -> Tuple[bool, str, Optional[RefStr]]:
+    """Splits a fetch refspec into (force, source, destination).
+
+    The destination is None if only a source is given.
+    """
+    force = refspec.startswith("+")
+    if force:
+        refspec = refspec[1:]
+    if refspec.count(":") > 1:
+        raise ValueError(f"Multiple ':' found in refspec {refspec}")
+    src, _, dst = refspec.partition(":")
+    if src == "":
+        raise ValueError(f"Missing source in refspec {refspec}")
+    if src.count("*") > 1 or dst.count("*") > 1:
+        raise ValueError(f"Only a single '*' is allowed in refspec {refspec}")
+    if dst != "" and src.count("*") != dst.count("*"):
+        raise ValueError(f"Both or neither sides need a '*' in refspec {refspec}")
+    return force, src, dst or None
+
+
+def is_toprepo_managed_ref(ref: RefStr, fetch_ref_prefix: str) -> bool:
+    """Tells whether git-toprepo rewrites ref by itself, which makes it
+    unusable as the destination of a fetch refspec."""
+    managed_prefixes = [
+        "refs/remotes/origin/",
+        REPOS_REF_PREFIX,
+        "refs/toprepo/",
+        fetch_ref_prefix,
+    ]
+    return any(ref.startswith(prefix) for prefix in managed_prefixes)
+
+
+def expand_glob_refspec(
+    src: str, dst: Optional[RefStr], remote_refs: Iterable[RefStr]
+) -> List[Tuple[RefStr, Optional[RefStr]]]:
+    """Expands a refspec with '*' into the matching remote refs.
+
+    Like git, '*' matches any part of a ref, including slashes, and the
+    matched part replaces '*' in the destination. A source without '*' is
+    returned as is.
+    """
+    if "*" not in src:
+        return [(src, dst)]
+    prefix, suffix = src.split("*")
+    expanded: List[Tuple[RefStr, Optional[RefStr]]] = []
+    for ref in sorted(remote_refs):
+        if (
+            len(ref) < len(prefix) + len(suffix)
+            or not ref.startswith(prefix)
+            or not ref.endswith(suffix)
+        ):
+            continue
+        matched = ref[len(prefix) : len(ref) - len(suffix)]
+        expanded.append((ref, None if dst is None else dst.replace("*", matched)))
+    return expanded
+
+
+def list_remote_refs(monorepo: MonoRepo, repo: Union[TopRepo, SubRepo]) -> List[RefStr]:
+    ls_remote = subprocess.check_output(
+        ["git", "-C", str(monorepo.path)]
+        + repo.config.git_config_args()
+        + ["ls-remote", "--refs", repo.config.fetch_url],
+        text=True,
+    )
+    return [line.split("\t", 1)[1] for line in ls_remote.splitlines() if line]
+
+
+def fetch_stdin_refs(
+    args,
+    monorepo: MonoRepo,
+    toprepo: TopRepo,
+    config: Config,
+    git_modules: List[GitModuleInfo],
+    topexpander: "TopRepoExpander",
+    subexpander: "SubrepoCommitExpander",
+    fetch_ref_namespace: str,
+) -> int:
+    """Fetches '<remote> <refspec>' lines from stdin and expands them together."""
+    remote_refspecs: List[Tuple[str, str]] = []
+    for line in sys.stdin.read().splitlines():
+        line = line.strip()
+        if line == "" or line.startswith("#"):
+            continue
+        parts = line.split()
+        if len(parts) != 2:
+            print(f"ERROR: Expected '<remote> <ref>' on stdin, got {line!r}")
+            return 1
+        remote, ref = parts
+        remote_refspecs.append((remote, ref))
+    if len(remote_refspecs) == 0:
+        print("ERROR: No refs to fetch were given on stdin")
+        return 1
+    return fetch_refs(
+        args,
+        monorepo,
+        toprepo,
+        config,
+        git_modules,
+        topexpander,
+        subexpander,
+        fetch_ref_namespace,
+        remote_refspecs,
+    )
+
+
+def fetch_refs(
+    args,
+    monorepo: MonoRepo,
+    toprepo: TopRepo,
+    config: Config,
+    git_modules: List[GitModuleInfo],
+    topexpander: "TopRepoExpander",
+    subexpander: "SubrepoCommitExpander",
+    fetch_ref_namespace: str,
+    remote_refspecs: List[Tuple[str, str]],
+) -> int:
+    """Fetches (remote, refspec) pairs and expands them together.
+
+    Refspecs with '*' are expanded into the matching refs of the remote.
+    Each ref ends up in <fetch_ref_namespace><idx> in the monorepo,
+    where <idx> is the zero based index of the expanded ref. If a refspec
+    has a destination, that monorepo ref is also updated. All fetched refs
+    are written to FETCH_HEAD, so a FETCH_HEAD destination needs no update.
+    """
+    # (idx, remote, ref, repo, subdir)
+    fetch_requests: List[Tuple[int, str, str, Union[TopRepo, SubRepo], bytes]] = []
+    # (idx, force, dest)
+    dest_refs: List[Tuple[int, bool, RefStr]] = []
+    for remote, refspec in remote_refspecs:
+        maybe = resolve_fetch_remote(monorepo, toprepo, config, git_modules, remote)
+        if maybe is None:
+            return 1
+        repo, subdir = maybe
+        try:
+            force, src, dst = parse_fetch_refspec(refspec)
+        except ValueError as err:
+            print(f"ERROR: {err}")
+            return 1
+        remote_refs = list_remote_refs(monorepo, repo) if "*" in src else []
+        expanded = expand_glob_refspec(src, dst, remote_refs)
+        if len(expanded) == 0:
+            print(f"ERROR: No refs in {remote} match {src}")
+            return 1
+        for ref, dest in expanded:
+            if dest == "FETCH_HEAD":
+                dest = None
+            elif dest is not None and is_toprepo_managed_ref(
+                dest, config.fetch_ref_prefix
+            ):
+                print(f"ERROR: Cannot fetch into {dest}, git-toprepo manages it")
+                return 1
+            idx = len(fetch_requests)
+            fetch_requests.append((idx, remote, ref, repo, subdir))
+            if dest is not None:
+                dest_refs.append((idx, force, dest))
+    if len(dest_refs) != 0 and not args.do_filter:
+        print("ERROR: Refspec destinations cannot be combined with --skip-filter")
+        return 1
+
+    # Fetch all refs from each repository in one go.
+    repo_to_fetch_args: Dict[RepoName, Tuple[Union[TopRepo, SubRepo], List[str]]] = {}
+    for idx, _, ref, repo, _ in fetch_requests:
+        _, ref_args = repo_to_fetch_args.setdefault(repo.name, (repo, []))
+        ref_args.append(f"+{ref}:{fetch_ref_namespace}{idx}")
+    for repo, ref_args in repo_to_fetch_args.values():
+        RepoFetcher(monorepo, args.apply_redirects).fetch_repo(repo, ref_args)
+    if not args.do_filter:
+        PendingFilters(monorepo).add(
+            [
+                {
+                    "repo": repo.name,
+                    "subdir": subdir.decode("utf-8"),
+                    "ref": f"{fetch_ref_namespace}{idx}",
+                    "description": f"{ref} of {remote}",
+                }
+                for idx, remote, ref, repo, subdir in fetch_requests
+            ]
+        )
+        print(f"Fetched {fetch_ref_namespace}* but skipped the monorepo filtering.")
+        return 0
+
+    # A single filtering pass for all the top repository refs.
+    top_fetch_head_refs = [
+        f"{fetch_ref_namespace}{idx}"
+        for idx, _, _, repo, _ in fetch_requests
+        if repo.is_top
+    ]
+    if len(top_fetch_head_refs) != 0:
+        if not topexpander.expand_toprepo(
+            top_refs=top_fetch_head_refs + ["--all"],
+            allow_fetching=True,
+            abort_on_missing=args.abort_on_missing,
+        ):
+            return 1
+    for idx, _, _, repo, subdir in fetch_requests:
+        if not repo.is_top:
+            subrepo_ref = repo_ref(repo.name, f"{fetch_ref_namespace}{idx}")
+            if not subexpander.expand_subrepo_refs(
+                subdir, subrepo_ref, dest_ref=f"{fetch_ref_namespace}{idx}"
+            ):
+                return 1
+    for idx, remote, ref, _, _ in fetch_requests:
+        print(f"{fetch_ref_namespace}{idx} {remote} {ref}")
+    if not update_dest_refs(monorepo, fetch_ref_namespace, dest_refs):
+        return 1
+    update_fetch_head(
+        monorepo,
+        [
+            (f"{fetch_ref_namespace}{idx}", f"{ref} of {remote}")
+            for idx, remote, ref, _, _ in fetch_requests
+        ],
+    )
+    return 0
+
+
+ref_update_chunk_size = 10000
+"""Number of refs to update per git-update-ref transaction, between progress."""
+
+
+def update_dest_refs(
+    monorepo: MonoRepo,
+    fetch_ref_namespace: str,
+    dest_refs: List[Tuple[int, bool, RefStr]],
+) -> bool:
+    """Points the destinations of the fetched refspecs to the expanded commits.
+
+    Like git-fetch, only fast-forwards are allowed unless the refspec
+    starts with '+'.
+    """
+    ok = True
+    # A glob refspec can match thousands of refs, avoid one git call per ref.
+    existing_refs: Dict[RefStr, str] = {}
+    # <hash> SP <refname> LF
+    for line in subprocess.check_output(
+        ["git", "-C", str(monorepo.path), "for-each-ref"]
+        + ["--format=%(objectname) %(refname)"],
+        text=True,
+    ).splitlines():
+        object_hash, refname = line.split(" ", 1)
+        existing_refs[refname] = object_hash
+    fetched_hashes = resolve_objects(
+        monorepo,
+        [f"{fetch_ref_namespace}{idx}".encode("utf-8") for idx, _, _ in dest_refs],
+    )
+    update_ref_instructions = []
+    for (_, force, dest), fetched_hash in zip(dest_refs, fetched_hashes):
+        assert fetched_hash is not None, dest
+        new_hash = fetched_hash.decode("utf-8")
+        old_hash = existing_refs.get(dest)
+        if old_hash == new_hash:
+            continue
+        if not force and old_hash is not None:
+            is_ancestor = subprocess.run(
+                ["git", "-C", str(monorepo.path), "merge-base", "--is-ancestor"]
+                + [old_hash, new_hash],
+                check=False,
+            )
+            if is_ancestor.returncode != 0:
+                print(f"ERROR: Rejected non-fast-forward update of {dest}")
+                ok = False
+                continue
+        update_ref_instructions.append(f"update {dest} {new_hash}\n")
+    for start in range(0, len(update_ref_instructions), ref_update_chunk_size):
+        chunk = update_ref_instructions[start : start + ref_update_chunk_size]
+        subprocess.run(
+            ["git", "-C", str(monorepo.path), "update-ref"]
+            + ["-m", "toprepo fetch", "--stdin"],
+            input="".join(chunk),
+            text=True,
+            check=True,
+        )
+        if len(update_ref_instructions) > ref_update_chunk_size:
+            print(
+                f"Updated {start + len(chunk)} of {len(update_ref_instructions)} refs",
+                file=sys.stderr,
+            )
+    return ok
+
+
+def main_filter_pending(args) -> int:
+    monorepo = MonoRepo(args.cwd)
+    pending_filters = PendingFilters(monorepo)
+    entries = pending_filters.load()
+    if len(entries) == 0:
+        print("Nothing fetched with --skip-filter is pending.")
+        return 0
+    config_dict = ConfigAccumulator(monorepo, online=False).try_load_main_config()
+    if config_dict is None:
+        return 1
+    config = Config.try_create(config_dict)
+    if config is None:
+        return 1
+    toprepo = TopRepo.from_config(monorepo.get_toprepo_dir(), config)
+    topexpander = TopRepoExpander(monorepo, toprepo, config)
+    subexpander = SubrepoCommitExpander(
+        monorepo,
+        config.reencode_messages,
+        config.trim_submodule_updates,
+        config.message_rewrites,
+    )
+
+    ref_entries = []
+    for entry in entries:
+        if entry["ref"] is None:
+            continue
+        # The fetched ref is mirrored from the top or sub repository.
+        fetched_ref = repo_ref(entry["repo"], entry["ref"])
+        if not ref_exists(monorepo, fetched_ref):
+            print(
+                f"WARNING: {entry['ref']} for {entry['description']} is gone, "
+                + "please fetch it again"
+            )
+            continue
+        ref_entries.append(entry)
+    top_refs = [entry["ref"] for entry in ref_entries if entry["repo"] == TopRepo.name]
+    if len(top_refs) != 0 or any(entry["ref"] is None for entry in entries):
+        if not topexpander.expand_toprepo(
+            top_refs=top_refs + ["--all"],
+            allow_fetching=True,
+            abort_on_missing=False,
+        ):
+            return 1
+    for entry in ref_entries:
+        if entry["repo"] != TopRepo.name:
+            subrepo_ref = repo_ref(entry["repo"], entry["ref"])
+            if not subexpander.expand_subrepo_refs(
+                entry["subdir"].encode("utf-8"), subrepo_ref, dest_ref=entry["ref"]
+            ):
+                return 1
+    for entry in ref_entries:
+        print(f"{entry['ref']} {entry['description']}")
+    if len(ref_entries) != 0:
+        update_fetch_head(
+            monorepo, [(entry["ref"], entry["description"]) for entry in ref_entries]
+        )
+    pending_filters.clear()
+    return 0
+
+
+def is_stale_fetch_ref(ref: RefStr, fetch_ref_prefix: str, now: float) -> bool:
+    """Checks if a ref is a leftover from an earlier 'git-toprepo fetch'.
+
+    The fetched refs are stored as <fetch_ref_prefix><time>-<pid>/<name>,
+    also mirrored as refs/repos/<repo-name>/... in the monorepo.
+    Refs without a parsable namespace are considered stale.
+    """
+    if ref.startswith(REPOS_REF_PREFIX):
+        maybe = split_repo_ref(ref)
+        if maybe is None:
+            return False
+        ref = maybe[1]
+    if not ref.startswith(fetch_ref_prefix):
+        return False
+    namespace = ref[len(fetch_ref_prefix) :].split("/", 1)[0]
+    match = re.fullmatch(r"([0-9]+)-[0-9]+", namespace)
+    if match is None:
+        return True
+    return now - int(match.group(1)) > stale_fetch_refs_seconds
+
+
+def delete_stale_fetch_refs(monorepo: MonoRepo, fetch_ref_prefix: str, now: float):
+    """Deletes old fetched refs in the monorepo and in the repos it fetches from.
+
+    Refs that are still pending filtering are kept.
+    """
+    pending_refs = PendingFilters(monorepo).refs()
+    repos: List[Repo] = [monorepo]
+    repos_dir = monorepo.git_dir / "repos"
+    if repos_dir.is_dir():
+        repos += [Repo(path) for path in sorted(repos_dir.iterdir()) if path.is_dir()]
+    for repo in repos:
+        refs = subprocess.check_output(
+            ["git", "-C", str(repo.path), "for-each-ref", "--format=%(refname)"],
+            text=True,
+        ).splitlines()
+        stale_refs = [
+            ref
+            for ref in refs
+            if is_stale_fetch_ref(ref, fetch_ref_prefix, now)
+            and strip_repos_ref_prefix(ref) not in pending_refs
+        ]
+        delete_refs(repo, stale_refs)
+    git_dirs = [monorepo.git_dir] + [repo.path for repo in repos[1:]]
+    for git_dir in git_dirs:
+        for path in delete_stale_partial_fetches(git_dir, now):
+            print(f"Removed {path} left behind by an interrupted fetch")
+
+
+def delete_stale_partial_fetches(git_dir: Path, now: float) -> List[Path]:
+    """Deletes temporary object files left behind by an interrupted git-fetch.
+
+    git-fetch writes packs to objects/pack/tmp_* and quarantines objects in
+    objects/incoming-* and objects/tmp_objdir-*, which are renamed when
+    complete. Leftovers are not part of the object store but waste space
+    until 'git gc' eventually removes them. Only entries older than the
+    stale fetch refs are removed, to not break concurrent fetches.
+    """
+    objects_dir = git_dir / "objects"
+    candidates = list((objects_dir / "pack").glob("tmp_*"))
+    candidates += list(objects_dir.glob("incoming-*"))
+    candidates += list(objects_dir.glob("tmp_objdir-*"))
+    removed: List[Path] = []
+    for path in sorted(candidates):
+        try:
+            if now - path.stat().st_mtime <= stale_fetch_refs_seconds:
+                continue
+            if path.is_dir():
+                shutil.rmtree(path)
+            else:
+                path.unlink()
+        except FileNotFoundError:
+            # Removed by a concurrent invocation.
+            continue
+        removed.append(path)
+    return removed
+
+
+REPOS_REF_PREFIX = "refs/repos/"
+"""The refs of each fetched repository are mirrored into the monorepo as
+refs/repos/<repo-name>/<name> for each refs/<name>, where the top repository
+is called "top". The mapping is stable and can be used by other tools.
+"""
+
+
+def repo_ref_prefix(name: RepoName) -> RefStr:
+    """Returns the prefix of the refs of a repository in the monorepo."""
+    return f"{REPOS_REF_PREFIX}{name}/"
+
+
+def repo_ref(name: RepoName, ref: RefStr) -> RefStr:
+    """Translates refs/<name> in a repository into its ref in the monorepo."""
+    assert ref.startswith("refs/"), ref
+    return repo_ref_prefix(name) + ref[len("refs/") :]
+
+
+def split_repo_ref(ref: RefStr) -> Optional[Tuple[RepoName, RefStr]]:
+    """Translates a monorepo ref refs/repos/<repo-name>/<name> into
+    (<repo-name>, refs/<name>), or returns None for other refs."""
+    if not ref.startswith(REPOS_REF_PREFIX):
+        return None
+    parts = ref[len(REPOS_REF_PREFIX) :].split("/", 1)
+    if len(parts) != 2 or parts[0] == "" or parts[1] == "":
+        return None
+    return parts[0], "refs/" + parts[1]
+
+
+def strip_repos_ref_prefix(ref: RefStr) -> RefStr:
+    """Translates refs/repos/<repo-name>/<name> into refs/<name>."""
+    maybe = split_repo_ref(ref)
+    if maybe is not None:
+        return maybe[1]
+    return ref
+
+
+def update_fetch_head(monorepo: MonoRepo, entries: List[Tuple[str, str]]):
+    """Writes FETCH_HEAD for the expanded (ref, description) entries.
+
+    Each entry is also recorded in the FETCH_HEAD reflog, so that earlier
+    fetches are reachable as FETCH_HEAD@{n}. The FETCH_HEAD file itself
+    gets the same format as from git-fetch, where only the first entry
+    is for merge.
+    """
+    commit_ids = resolve_objects(
+        monorepo, [f"{ref}^{{commit}}".encode("utf-8") for ref, _ in entries]
+    )
+    lines = []
+    for idx, ((ref, description), commit_id) in enumerate(zip(entries, commit_ids)):
+        if commit_id is None:
+            raise ValueError(f"{ref} is not a commit")
+        not_for_merge = "not-for-merge" if idx != 0 else ""
+        lines.append(f"{commit_id.decode('utf-8')}\t{not_for_merge}\t{description}\n")
+    # Record the first entry last, so that it ends up as FETCH_HEAD@{0}.
+    for (_, description), commit_id in reversed(list(zip(entries, commit_ids))):
+        assert commit_id is not None
+        subprocess.check_call(
+            ["git", "-C", str(monorepo.path)]
+            + ["update-ref", "--create-reflog", "--no-deref"]
+            + ["-m", f"toprepo fetch: {description}", "FETCH_HEAD"]
+            + [commit_id.decode("utf-8")],
+        )
+    # update-ref only stores one commit, overwrite with all the entries.
+    (monorepo.git_dir / "FETCH_HEAD").write_text("".join(lines), encoding="utf-8")
+
+
+def main_bump(args) -> int:
+    monorepo = MonoRepo(args.cwd)
+    config_dict = ConfigAccumulator(monorepo, online=True).try_load_main_config()
+    if config_dict is None:
+        return 1
+    config = Config.try_create(config_dict)
+    if config is None:
+        return 1
+    toprepo = TopRepo.from_config(monorepo.get_toprepo_dir(), config)
+    git_modules = get_gitmodules_info(
+        LocalFileConfigLoader(monorepo.path / ".gitmodules", allow_missing=True),
+        monorepo.get_toprepo_fetch_url(),
+    )
+    subdir_to_gitmod = {str(gitmod.path): gitmod for gitmod in git_modules}
+
+    # Find the latest local change for each subrepo.
+    mono_refs = get_remote_origin_refs(monorepo)
+    rev_list = subprocess.check_output(
+        ["git", "-C", str(monorepo.path), "rev-list", "--reverse", args.mono_rev]
+        + ["--not"]
+        + mono_refs,
+        text=True,
+    ).split()
+    subdir_to_change_id: Dict[str, str] = {}
+    for commit_hash in rev_list:
+        message = subprocess.check_output(
+            ["git", "-C", str(monorepo.path)]
+            + ["show", "--quiet", "--format=%B", commit_hash, "--"],
+        )
+        change_id = try_get_change_id_from_message(message)
+        changed_files = subprocess.check_output(
+            ["git", "-C", str(monorepo.path), "diff-tree", "--no-commit-id"]
+            + ["--name-only", "-r", "-z", commit_hash],
+            text=True,
+        ).split("\0")
+        for filename in changed_files:
+            for subdir in subdir_to_gitmod:
+                if filename.startswith(subdir + "/"):
+                    if change_id is None:
+                        print(f"ERROR: Commit {commit_hash} has no Change-Id footer")
+                        return 1
+                    subdir_to_change_id[subdir] = change_id
+    if len(subdir_to_change_id) == 0:
+        print(f"ERROR: No sub repository changes found in {args.mono_rev}")
+        return 1
+
+    # Find the merged commits upstream.
+    repo_fetcher = RepoFetcher(monorepo)
+    repo_fetcher.fetch_repo(toprepo)
+    new_submod_hashes: Dict[str, str] = {}
+    for subdir, change_id in sorted(subdir_to_change_id.items()):
+        repo_configs = config.raw_url_to_repos.get(subdir_to_gitmod[subdir].raw_url)
+        if repo_configs is None or len(repo_configs) != 1:
+            print(f"ERROR: Cannot resolve a single repository for {subdir}")
+            return 1
+        (repo_config,) = repo_configs
+        subrepo = SubRepo(repo_config, monorepo.get_subrepo_dir(repo_config.name))
+        repo_fetcher.fetch_repo(subrepo)
+        if config.change_id_per_repo:
+            change_id = repo_change_id(change_id, subrepo.name)
+        merged_hash = subprocess.check_output(
+            ["git", "-C", str(subrepo.path), "log", "--branches", "-1"]
+            + ["--format=%H", f"--grep=^Change-Id: {change_id}$"],
+            text=True,
+        ).strip()
+        if merged_hash == "":
+            print(f"ERROR: Change {change_id} in {subdir} is not merged upstream")
+            return 1
+        new_submod_hashes[subdir] = merged_hash
+
+    # Create the top commit in a temporary index in the monorepo,
+    # to commit with the user's identity.
+    top_parent = repo_ref(toprepo.name, f"refs/heads/{args.branch}")
+    index_file = monorepo.git_dir.absolute() / "toprepo" / "bump-index"
+    index_file.parent.mkdir(parents=True, exist_ok=True)
+    env = dict(os.environ, GIT_INDEX_FILE=str(index_file))
+    try:
+        subprocess.check_call(
+            ["git", "-C", str(monorepo.path), "read-tree", top_parent], env=env
+        )
+        for subdir, merged_hash in new_submod_hashes.items():
+            subprocess.check_call(
+                ["git", "-C", str(monorepo.path), "update-index", "--cacheinfo"]
+                + [f"160000,{merged_hash},{subdir}"],
+                env=env,
+            )
+        tree_hash = subprocess.check_output(
+            ["git", "-C", str(monorepo.path), "write-tree"], env=env, text=True
+        ).strip()
+    finally:
+        index_file.unlink(missing_ok=True)
+    message = "Update git submodules\n\n" + "".join(
+        f"* Update {subdir} to {merged_hash}\n"
+        for subdir, merged_hash in new_submod_hashes.items()
+    )
+    bump_hash = subprocess.check_output(
+        ["git", "-C", str(monorepo.path), "commit-tree", tree_hash]
+        + ["-p", top_parent, "-m", message],
+        text=True,
+    ).strip()
+    print(f"Created top repository commit {bump_hash}")
+    print(textwrap.indent(message, "  "))
+    if args.push_ref is not None:
+        log_run_git(
+            monorepo.path,
+            toprepo.config.git_config_args()
+            + ["push", "--quiet", toprepo.config.push_url]
+            + [f"{bump_hash}:{args.push_ref}"],
+            dry_run=args.dry_run,
+        )
+    return 0
+
+
+def merge_push_options(defaults: List[str], overrides: List[str]) -> List[str]:
+    """Appends overrides to the defaults.
+
+    Gerrit rejects 'wip' together with 'ready', so an override removes
+    the opposite default.
+    """
+    opposites = {"wip": "ready", "ready": "wip"}
+    removed = {opposites[option] for option in overrides if option in opposites}
+    ret = [option for option in defaults if option not in removed]
+    unique_extend(ret, overrides)
+    return ret
+
+
+def remote_branch_exists(repo: Union[TopRepo, SubRepo], ref: RefStr) -> bool:
+    ls_remote = subprocess.check_output(
+        ["git", "-C", str(repo.path)]
+        + repo.config.git_config_args()
+        + ["ls-remote", repo.config.push_url, ref],
+    )
+    return len(ls_remote.strip()) != 0
+
+
+def verify_branch_creation(
+    refspec_to_pushes: List[Tuple[PushRefSpec, Dict[RepoName, List[PushInstruction]]]],
+    create_branch: bool,
+) -> bool:
+    """Checks, before pushing anything, that branches missing upstream may be
+    created according to --create-branch and toprepo.repo.<name>.createBranches.
+
+    The server's own permissions are not checked.
+    """
+    ok = True
+    for refspec, repo_to_pushes in refspec_to_pushes:
+        if not refspec.remote_ref.startswith("refs/heads/"):
+            # E.g. Gerrit's refs/for/<branch> always exists.
+            continue
+        for push_list in repo_to_pushes.values():
+            repo = push_list[-1].repo
+            if remote_branch_exists(repo, refspec.remote_ref):
+                continue
+            if not create_branch:
+                print(
+                    f"ERROR: {refspec.remote_ref} does not exist in {repo.name}, "
+                    + "use --create-branch to create it"
+                )
+                ok = False
+            elif not repo.config.create_branches:
+                print(
+                    f"ERROR: Creating {refspec.remote_ref} in {repo.name} is denied "
+                    + f"by toprepo.repo.{repo.name}.createBranches"
+                )
+                ok = False
+    return ok
+
+
+def split_for_push(
+    monorepo: MonoRepo,
+    toprepo: TopRepo,
+    config: Config,
+    bump_top: bool,
+    refspecs: List[PushRefSpec],
+    local_refs: List[RefStr],
+) -> Optional[List[Tuple[PushRefSpec, Dict[RepoName, List[PushInstruction]]]]]:
+    """Splits the monorepo commits to push, per refspec and repository.
+
+    local_refs are the refs to split for each refspec, resolved from the
+    committish on the local side.
+    """
+    splitter = PushSplitter(monorepo, toprepo, config, bump_top=bump_top)
+
+    # A single split pass for all the refspecs.
+    try:
+        push_instructions = splitter.split_commits(local_refs)
+    except PushSplitError as err:
+        print(f"\nERROR: {err}")
+        return None
+
+    # Fan out the split commits per refspec.
+    mono_refs = get_remote_origin_refs(monorepo)
+    refspec_to_pushes: List[Tuple[PushRefSpec, Dict[RepoName, List[PushInstruction]]]]
+    refspec_to_pushes = []
+    for refspec, local_ref in zip(refspecs, local_refs):
+        mono_hashes = set(
+            subprocess.check_output(
+                ["git", "-C", str(monorepo.path), "rev-list", local_ref]
+                + ["--not"]
+                + mono_refs
+                + ["--"],
+            ).split()
+        )
+        # Sort per branch and remove unnecessary pushes.
+        repo_to_pushes: DefaultDict[RepoName, List[PushInstruction]] = defaultdict(
+            list
+        )
+        for new_push in push_instructions:
+            if new_push.mono_commit_hash not in mono_hashes:
+                continue
+            push_list = repo_to_pushes[new_push.repo.name]
+            if len(push_list) != 0 and push_list[-1].same_but_commit(new_push):
+                push_list.pop()
+            push_list.append(new_push)
+        refspec_to_pushes.append((refspec, repo_to_pushes))
+    return refspec_to_pushes
+
+
+def main_push(args) -> int:
     monorepo = MonoRepo(args.cwd)
     config_dict = ConfigAccumulator(monorepo, online=True).try_load_main_config()
+    if config_dict is None:
+        return 1
+    remote = args.remote
+    refspecs: List[PushRefSpec] = args.refspecs
+    if remote is not None and ":" in remote:
+        # Not a remote, but the first of multiple refspecs.
+        refspecs = [PushRefSpec.parse(remote)] + refspecs
+        remote = None
+    if remote is None:
+        remote = config_dict.get("remote.pushdefault", [None])[-1]
+    if remote not in (None, "origin", "top"):
+        # Push the top repository to another remote in the monorepo,
+        # relative sub repository URLs follow along.
+        remote_url = config_dict.get(
+            f"remote.{remote}.pushurl", config_dict.get(f"remote.{remote}.url", [None])
+        )[-1]
+        if remote_url is None:
+            print(f"ERROR: Neither remote.{remote}.pushUrl nor url is configured")
+            return 1
+        config_dict["remote.top.pushurl"] = [remote_url]
+    config = Config.try_create(config_dict)
+    if config is None:
+        return 1
+    toprepo = TopRepo.from_config(monorepo.get_toprepo_dir(), config)
+
+    # The local side can be any committish, e.g. HEAD~2 in a detached
+    # checkout. git-filter-repo needs refs, so point temporary refs at them.
+    local_hashes: List[str] = []
+    for refspec in refspecs:
+        result = subprocess.run(
+            ["git", "-C", str(monorepo.path), "rev-parse", "--verify", "--quiet"]
+            + [f"{refspec.local_ref}^{{commit}}"],
+            check=False,
+            stdout=subprocess.PIPE,
+            text=True,
+        )
+        if result.returncode != 0:
+            print(f"ERROR: {refspec.local_ref} is not a commit in the monorepo")
+            return 1
+        local_hashes.append(result.stdout.strip())
+    local_ref_namespace = f"refs/toprepo/push-local/{int(time.time())}-{os.getpid()}/"
+    local_refs = [f"{local_ref_namespace}{idx}" for idx in range(len(refspecs))]
+    subprocess.run(
+        ["git", "-C", str(monorepo.path), "update-ref", "--stdin"],
+        input="".join(
+            f"update {ref} {commit_hash}\n"
+            for ref, commit_hash in zip(local_refs, local_hashes)
+        ),
+        text=True,
+        check=True,
+    )
+    try:
+        refspec_to_pushes = split_for_push(
+            monorepo, toprepo, config, args.bump_top, refspecs, local_refs
+        )
+    finally:
+        delete_refs(monorepo, local_refs)
+    if refspec_to_pushes is None:
+        return 1
+
+    # Make the commits available in each repository to push from.
+    for _, repo_to_pushes in refspec_to_pushes:
+        for push_list in repo_to_pushes.values():
+            repo = push_list[-1].repo
+            log_run_git(
+                monorepo.path,
+                ["push", "--quiet", "--force", str(repo.path.absolute())]
+                + [f"{push_list[-1].commit_hash.decode('utf-8')}:refs/toprepo/push"],
+                log_command=False,
+            )
+
+    # Gerrit specific push options.
+    cli_push_options = []
+    if args.wip:
+        cli_push_options.append("wip")
+    if args.ready:
+        cli_push_options.append("ready")
+    if args.private:
+        cli_push_options.append("private")
+    for hashtag in args.hashtags:
+        cli_push_options.append(f"hashtag={hashtag}")
+
+    # Checking the branches contacts the servers, which a dry run doesn't do.
+    if not args.dry_run and not verify_branch_creation(
+        refspec_to_pushes, args.create_branch
+    ):
+        return 1
+
+    # Push the sub repositories for all the refspecs before the top repository,
+    # so that the top repository is not updated when any sub repository push
+    # fails.
+    pushes = [
+        (refspec, repo_name, push)
+        for refspec, repo_to_pushes in refspec_to_pushes
+        for repo_name, push_list in repo_to_pushes.items()
+        for push in push_list
+    ]
+    pushes.sort(key=lambda item: item[1] == TopRepo.name)
+    for refspec, repo_name, push in pushes:
+        push_rev = push.commit_hash.decode("utf-8")
+        push_options = merge_push_options(
+            push.repo.config.push_options, cli_push_options
+        )
+        result = log_run_git(
+            push.repo.path,
+            push.repo.config.git_config_args()
+            + ["push", "--quiet", push.repo.config.push_url]
+            + [f"{push_rev}:{refspec.remote_ref}"]
+            + push.extra_args
+            + [arg for option in push_options for arg in ("-o", option)],
+            log_command=True,
+            dry_run=args.dry_run,
+            check=False,
+        )
+        if result is not None and result.returncode != 0:
+            print(f"ERROR: Failed to push {repo_name}, skipping the remaining pushes")
+            return 1
+    return 0
+
+
+def main_status(args) -> int:
+    monorepo = MonoRepo(args.cwd)
+    config_dict = ConfigAccumulator(monorepo, online=False).try_load_main_config()
+    if config_dict is None:
+        return 1
+    config = Config.try_create(config_dict)
+    if config is None:
+        return 1
+    toprepo = TopRepo.from_config(monorepo.get_toprepo_dir(), config)
+    if not toprepo.path.exists():
+        print("The top repository has not been fetched, run 'git toprepo fetch'.")
+        return 0
+
+    print("Top repository branches:")
+    top_branches = get_branch_commits(toprepo)
+    for ref, top_hash in sorted(top_branches.items()):
+        branch = ref[len("refs/heads/") :]
+        mono_ref = f"refs/remotes/origin/{branch}"
+        if not ref_exists(monorepo, mono_ref):
+            state = "not expanded"
+        else:
+            mono_message = subprocess.check_output(
+                ["git", "-C", str(monorepo.path)]
+                + ["show", "--quiet", "--format=%B", mono_ref, "--"],
+            )
+            expanded_hash = try_parse_top_hash_from_message(mono_message)
+            if expanded_hash == top_hash.encode("utf-8"):
+                state = "up to date"
+            else:
+                state = "outdated"
+        print(f"  {branch}: {state}")
+
+    # Only look at the branch tips, to stay fast.
+    referenced: DefaultDict[RepoName, Set[CommitHash]] = defaultdict(set)
+    for top_hash in sorted(set(top_branches.values())):
+        gitlinks = get_top_commit_gitlinks(toprepo, config, top_hash)
+        for repo_name, submod_hash in gitlinks:
+            referenced[repo_name].add(submod_hash)
+    print("Sub repositories:")
+    for repo_config in sorted(config.repos, key=lambda repo_config: repo_config.name):
+        if not repo_config.enabled:
+            print(f"  {repo_config.name}: not selected by the role")
+            continue
+        subrepo = SubRepo(repo_config, monorepo.get_subrepo_dir(repo_config.name))
+        if not subrepo.path.exists():
+            state = "not fetched"
+            missing_count = len(referenced[repo_config.name])
+        else:
+            state = "fetched"
+            object_types = get_object_types(subrepo, referenced[repo_config.name])
+            missing_count = sum(
+                object_type != "commit" for object_type in object_types.values()
+            )
+        print(
+            f"  {repo_config.name}: {state}, {missing_count} of "
+            + f"{len(referenced[repo_config.name])} commits at the branch tips missing"
+        )
+    return 0
+
+
+def get_top_commit_gitlinks(
+    toprepo: TopRepo, config: Config, top_hash: str
+) -> List[Tuple[RepoName, CommitHash]]:
+    """Returns the configured repository and commit of each submodule."""
+    # <mode> SP <type> SP <object> TAB <path>
+    ls_tree = subprocess.check_output(
+        ["git", "-C", str(toprepo.path), "ls-tree", "-r", "-z", top_hash],
+    )
+    path_to_hash: Dict[PurePosixPath, CommitHash] = {}
+    for entry in ls_tree.split(b"\0"):
+        if entry.startswith(b"160000 "):
+            info, path = entry.split(b"\t", 1)
+            path_to_hash[PurePosixPath(path.decode("utf-8"))] = info.split(b" ")[2]
+    if len(path_to_hash) == 0:
+        return []
+    gitmodules = get_gitmodules_info(
+        GitRemoteConfigLoader(
+            url="",
+            remote_ref="",
+            filename=PurePosixPath(".gitmodules"),
+            local_repo=toprepo,
+            local_ref=top_hash,
+        ),
+        toprepo.config.fetch_url,
+    )
+    ret = []
+    for gitmod in gitmodules:
+        submod_hash = path_to_hash.get(gitmod.path)
+        if submod_hash is None:
+            continue
+        for repo_config in config.raw_url_to_repos.get(gitmod.raw_url, []):
+            ret.append((repo_config.name, submod_hash))
+    return ret
+
+
+def resolve_objects(repo: Repo, exprs: List[bytes]) -> List[Optional[bytes]]:
+    """Resolves each <rev>:<path>-like expression, None if it doesn't exist."""
+    if len(exprs) == 0:
+        return []
+    # <hash> LF, or <expr> SP missing LF
+    output = subprocess.check_output(
+        ["git", "-C", str(repo.path), "cat-file", "--batch-check=%(objectname)"],
+        input=b"".join(expr + b"\n" for expr in exprs),
+    )
+    return [line if b" " not in line else None for line in output.splitlines()]
+
+
+def differs_outside_gitlinks(repo: Repo, sub_tree: bytes, mono_tree: bytes) -> bool:
+    """Compares a sub repository tree with its expansion in the monorepo.
+
+    Nested submodules are gitlinks in sub_tree but may be expanded in
+    mono_tree, so differences below gitlinks are ignored.
+    """
+    # :<old mode> SP <new mode> SP <old hash> SP <new hash> SP <status> NUL <path> NUL
+    diff = subprocess.check_output(
+        ["git", "-C", str(repo.path), "diff-tree", "-r", "-z", sub_tree, mono_tree],
+    ).split(b"\0")
+    changes = list(zip(diff[0:-1:2], diff[1::2]))
+    gitlink_paths = [path for info, path in changes if info.startswith(b":160000 ")]
+    for _, path in changes:
+        if not any(
+            path == gitlink or path.startswith(gitlink + b"/")
+            for gitlink in gitlink_paths
+        ):
+            return True
+    return False
+
+
+def verify_mono_commits(monorepo: MonoRepo, revs: List[str]) -> List[str]:
+    """Cross-checks the expanded commits against their annotations.
+
+    Checks that the annotated commits exist, that each expanded subdirectory
+    has the content of the annotated sub repository commit and that the top
+    commit refers to that sub repository commit.
+
+    Returns:
+        A description of each inconsistency.
+    """
+    # <hash> LF <message> NUL
+    log = subprocess.check_output(
+        ["git", "-C", str(monorepo.path), "log", "-z", "--format=%H%n%B"]
+        + revs
+        + ["--"],
+    )
+    checks: List[Tuple[bytes, bytes, Optional[bytes], bytes]] = []
+    for entry in log.split(b"\0"):
+        if entry == b"":
+            continue
+        mono_hash, message = entry.split(b"\n", 1)
+        annotations = parse_annotations(message)
+        top_hash = dict(annotations).get(ANNOTATED_TOP_SUBDIR)
+        for subdir, submod_hash in annotations:
+            checks.append((mono_hash, subdir, top_hash, submod_hash))
+
+    exprs: List[bytes] = []
+    for mono_hash, subdir, top_hash, submod_hash in checks:
+        exprs.append(submod_hash + b"^{commit}")
+        if subdir != ANNOTATED_TOP_SUBDIR:
+            exprs.append(submod_hash + b"^{tree}")
+            exprs.append(mono_hash + b":" + subdir)
+            if top_hash is not None:
+                # Nested submodules are not gitlinks in the top commit.
+                exprs.append(top_hash + b":" + subdir)
+    resolved = iter(resolve_objects(monorepo, exprs))
+
+    problems: List[str] = []
+    for mono_hash, subdir, top_hash, submod_hash in checks:
+        mono_str = mono_hash.decode("utf-8")
+        subdir_str = subdir.decode("utf-8")
+        submod_str = submod_hash.decode("utf-8")
+        if next(resolved) is None:
+            problems.append(f"{mono_str}: {subdir_str} commit {submod_str} is missing")
+        if subdir == ANNOTATED_TOP_SUBDIR:
+            continue
+        sub_tree = next(resolved)
+        mono_tree = next(resolved)
+        gitlink = next(resolved) if top_hash is not None else None
+        if sub_tree is not None and (
+            mono_tree is None
+            or (
+                mono_tree != sub_tree
+                and differs_outside_gitlinks(monorepo, sub_tree, mono_tree)
+            )
+        ):
+            problems.append(
+                f"{mono_str}: The content of {subdir_str} differs from "
+                + f"commit {submod_str}"
+            )
+        if gitlink is not None and gitlink != submod_hash:
+            assert top_hash is not None
+            problems.append(
+                f"{mono_str}: Top commit {top_hash.decode('utf-8')} refers to "
+                + f"{gitlink.decode('utf-8')} at {subdir_str}, not {submod_str}"
+            )
+    return problems
+
+
+def main_verify(args) -> int:
+    monorepo = MonoRepo(args.cwd)
+    revs = args.revs if len(args.revs) != 0 else ["--remotes=origin"]
+    problems = verify_mono_commits(monorepo, revs)
+    for problem in problems:
+        print(f"ERROR: {problem}")
+    if len(problems) != 0:
+        print("Run 'git toprepo refilter' to expand the history again.")
+        return 1
+    return 0
+
+
+def main_clean(args) -> int:
+    monorepo = MonoRepo(args.cwd)
+    ref_prefixes = [REPOS_REF_PREFIX, "refs/toprepo/"]
+    try:
+        config_dict = ConfigAccumulator(monorepo, online=False).load_main_config()
+        fetch_ref_prefix = config_dict.get(
+            "toprepo.fetchrefprefix", [default_fetch_ref_prefix]
+        )[-1]
+        ref_prefixes.append(fetch_ref_prefix.rstrip("/") + "/")
+    except (RuntimeError, ValueError, subprocess.CalledProcessError):
+        # The configuration is gone already, only the default prefix is known.
+        pass
+    config_keys: List[str] = []
+    if args.all:
+        ref_prefixes.append("refs/remotes/origin/")
+        if get_git_config(monorepo, "remote.origin.pushUrl") == "file:///dev/null":
+            config_keys.append("remote.origin.pushUrl")
+        if get_git_config(monorepo, "remote.top.pushUrl") is not None:
+            config_keys.append("remote.top.pushUrl")
+    refs = subprocess.check_output(
+        ["git", "-C", str(monorepo.path), "for-each-ref", "--format=%(refname)"]
+        + sorted(set(ref_prefixes)),
+        text=True,
+    ).splitlines()
+    dirs = [
+        path
+        for path in [
+            monorepo.git_dir / "repos",
+            monorepo.git_dir / "toprepo",
+            monorepo.git_dir / "filter-repo",
+        ]
+        if path.exists()
+    ]
+    if len(refs) == 0 and len(dirs) == 0 and len(config_keys) == 0:
+        print("Nothing to clean.")
+        return 0
+    verb = "Would remove" if args.dry_run else "Removing"
+    for path in dirs:
+        print(f"{verb} {path}")
+    for ref in refs:
+        print(f"{verb} {ref}")
+    for key in config_keys:
+        print(f"{verb} git-config {key}")
+    if args.dry_run:
+        return 0
+    delete_refs(monorepo, refs)
+    for path in dirs:
+        shutil.rmtree(path)
+    for key in config_keys:
+        log_run_git(monorepo.path, ["config", "--unset", key], log_command=False)
+    return 0
+
+
+def get_prunable_repos(
+    present: Iterable[RepoName], repo_configs: List[RepoConfig]
+) -> List[RepoName]:
+    """Returns the present repositories that are removed from the
+    configuration or not selected by the role."""
+    wanted = {TopRepo.name} | {
+        repo_config.name for repo_config in repo_configs if repo_config.enabled
+    }
+    return sorted(set(present) - wanted)
+
+
+def main_prune(args) -> int:
+    monorepo = MonoRepo(args.cwd)
+    config_dict = ConfigAccumulator(monorepo, online=False).try_load_main_config()
+    if config_dict is None:
+        return 1
+    config = Config.try_create(config_dict)
+    if config is None:
+        return 1
+    repos_refs = subprocess.check_output(
+        ["git", "-C", str(monorepo.path), "for-each-ref", "--format=%(refname)"]
+        + [REPOS_REF_PREFIX],
+        text=True,
+    ).splitlines()
+    present: Set[RepoName] = set()
+    for ref in repos_refs:
+        maybe = split_repo_ref(ref)
+        if maybe is not None:
+            present.add(maybe[0])
+    repos_dir = monorepo.git_dir / "repos"
+    if repos_dir.exists():
+        present.update(path.name for path in repos_dir.iterdir() if path.is_dir())
+    prunable = get_prunable_repos(present, config.repos)
+    if len(prunable) == 0:
+        print("Nothing to prune.")
+    verb = "Would remove" if args.dry_run else "Removing"
+    pending_filters = PendingFilters(monorepo)
+    for name in prunable:
+        refs = [ref for ref in repos_refs if ref.startswith(repo_ref_prefix(name))]
+        subrepo_dir = monorepo.get_subrepo_dir(name)
+        print(f"{verb} {len(refs)} refs under {repo_ref_prefix(name)}")
+        if subrepo_dir.exists():
+            print(f"{verb} {subrepo_dir}")
+        if args.dry_run:
+            continue
+        delete_refs(monorepo, refs)
+        if subrepo_dir.exists():
+            shutil.rmtree(subrepo_dir)
+    if not args.dry_run and len(prunable) != 0:
+        # Forget what was fetched with --skip-filter from the pruned repos.
+        entries = pending_filters.load()
+        kept_entries = [entry for entry in entries if entry["repo"] not in prunable]
+        if len(kept_entries) != len(entries):
+            pending_filters.clear()
+            if len(kept_entries) != 0:
+                pending_filters.add(kept_entries)
+    if args.gc and not args.dry_run:
+        log_run_git(monorepo.path, ["gc", "--quiet"])
+    return 0
+
+
+def get_git_config(repo: Repo, key: str) -> Optional[str]:
+    result = subprocess.run(
+        ["git", "-C", str(repo.path), "config", key],
+        check=False,
+        stdout=subprocess.PIPE,
+        text=True,
+    )
+    if result.returncode != 0:
+        return None
+    return result.stdout.rstrip("\n")
+
+
+def main_metrics_show(args) -> int:
+    monorepo = MonoRepo(args.cwd)
+    entries = ExpansionMetrics(monorepo).load()
+    if len(entries) == 0:
+        print("No expansion metrics recorded yet.")
+        return 0
+    entries = entries[-args.limit :]
+    phases = ["collect", "load", "expand", "update_refs"]
+    print(f"{'time':<20}" + "".join(f"{phase:>12}" for phase in phases + ["total"]))
+    totals: List[float] = []
+    for entry in entries:
+        seconds = entry["seconds"]
+        total = sum(seconds.get(phase, 0.0) for phase in phases)
+        totals.append(total)
+        print(
+            f"{entry['time']:<20}"
+            + "".join(f"{seconds.get(phase, 0.0):>12.2f}" for phase in phases)
+            + f"{total:>12.2f}"
+        )
+    half = len(totals) // 2
+    if half != 0:
+        older_average = sum(totals[:half]) / half
+        newer_average = sum(totals[-half:]) / half
+        print(
+            f"Average total: {older_average:.2f}s for the {half} older runs, "
+            + f"{newer_average:.2f}s for the {half} newer runs"
+        )
+    return 0
+
+
+codeowners_locations = ["CODEOWNERS", ".github/CODEOWNERS", "docs/CODEOWNERS"]
+"""Where GitHub looks for CODEOWNERS, in priority order."""
+
+codeowners_marker = "# Generated by git-toprepo codeowners, do not edit below."
+
+
+def prefix_codeowners_line(line: str, subdir: str) -> str:
+    """Makes a CODEOWNERS rule from a sub repository apply to its subdirectory.
+
+    The patterns follow the .gitignore rules, so a pattern without a slash,
+    apart from a trailing one, matches at any depth, while other patterns
+    are relative to the repository root.
+    """
+    stripped = line.strip()
+    if stripped == "" or stripped.startswith("#"):
+        return line
+    pattern, *owners = stripped.split()
+    if pattern.startswith("/"):
+        new_pattern = f"/{subdir}{pattern}"
+    elif "/" in pattern.rstrip("/"):
+        new_pattern = f"/{subdir}/{pattern}"
+    else:
+        new_pattern = f"/{subdir}/**/{pattern}"
+    return " ".join([new_pattern] + owners)
+
+
+def main_info(args) -> int:
+    if args.value == "repo":
+        if args.ref is None:
+            print("ERROR: --value repo requires --ref")
+            return 1
+        maybe = split_repo_ref(args.ref)
+        if maybe is None:
+            print(f"ERROR: {args.ref} is not under {REPOS_REF_PREFIX}<repo-name>/")
+            return 1
+        name, ref = maybe
+        print(f"{name} {ref}")
+        return 0
+    monorepo = MonoRepo(args.cwd)
+    config_dict = ConfigAccumulator(monorepo, online=False).try_load_main_config()
+    if config_dict is None:
+        return 1
+    config = Config.try_create(config_dict)
+    if config is None:
+        return 1
+    names = [TopRepo.name] + sorted(repo_config.name for repo_config in config.repos)
+    if args.value == "ref-prefix":
+        if args.repo not in names:
+            print(f"ERROR: {args.repo} is not a configured repository")
+            return 1
+        print(repo_ref_prefix(args.repo))
+        return 0
+    assert args.value is None, args.value
+    for name in names:
+        print(f"{name} {repo_ref_prefix(name)}")
+    return 0
+
+
+def find_git_dir_without_git(path: Path) -> Optional[Path]:
+    """Finds the common git directory of the repository containing path,
+    without running git, by following .git directories, .git files and
+    commondir files. Returns None if not inside a repository."""
+    path = path.absolute()
+    for directory in [path] + list(path.parents):
+        dot_git = directory / ".git"
+        if dot_git.is_dir():
+            git_dir = dot_git
+        elif dot_git.is_file():
+            content = dot_git.read_text(encoding="utf-8").strip()
+            if not content.startswith("gitdir: "):
+                return None
+            git_dir = directory / content[len("gitdir: ") :]
+        else:
+            continue
+        commondir = git_dir / "commondir"
+        if commondir.is_file():
+            git_dir = git_dir / commondir.read_text(encoding="utf-8").strip()
+        return git_dir
+    return None
+
+
+def format_shell_prompt(git_dir: Optional[Path], now: float) -> str:
+    """Returns the compact monorepo state for a shell prompt.
+
+    The output is empty outside of a monorepo, otherwise "toprepo", followed by
+    "pending:<count>" if fetches wait for 'git toprepo filter-pending' and
+    "stale" if the last expansion is more than a day old.
+    """
+    if git_dir is None or not (git_dir / "repos" / TopRepo.name).is_dir():
+        return ""
+    parts = ["toprepo"]
+    pending_path = git_dir / "toprepo" / "pending-filter.json"
+    if pending_path.is_file():
+        pending = json.loads(pending_path.read_text(encoding="utf-8"))
+        if len(pending) != 0:
+            parts.append(f"pending:{len(pending)}")
+    metrics_path = git_dir / "toprepo" / "metrics.json"
+    last_expansion = None
+    if metrics_path.is_file():
+        entries = json.loads(metrics_path.read_text(encoding="utf-8"))
+        if len(entries) != 0:
+            last_expansion = time.mktime(
+                time.strptime(entries[-1]["time"], "%Y-%m-%dT%H:%M:%S")
+            )
+    if last_expansion is None or now - last_expansion > stale_fetch_refs_seconds:
+        parts.append("stale")
+    return " ".join(parts)
+
+
+def main_shell_prompt(args) -> int:
+    prompt = format_shell_prompt(find_git_dir_without_git(args.cwd), time.time())
+    if prompt != "":
+        print(prompt)
+    return 0
+
+
+def main_codeowners(args) -> int:
+    monorepo = MonoRepo(args.cwd)
+    git_modules = get_gitmodules_info(
+        LocalFileConfigLoader(monorepo.path / ".gitmodules", allow_missing=True),
+        monorepo.get_toprepo_fetch_url(),
+    )
+
+    def read_committed_file(path: str) -> Optional[str]:
+        # Read from HEAD to not pick up the output from an earlier run.
+        result = subprocess.run(
+            ["git", "-C", str(monorepo.path), "cat-file", "blob", f"HEAD:{path}"],
+            check=False,
+            stdout=subprocess.PIPE,
+            stderr=subprocess.DEVNULL,
+        )
+        if result.returncode != 0:
+            return None
+        return result.stdout.decode("utf-8")
+
+    lines: List[str] = []
+    for location in codeowners_locations:
+        content = read_committed_file(location)
+        if content is not None:
+            # Skip the generated part if the output has been committed.
+            lines += content.split(codeowners_marker + "\n", 1)[0].splitlines()
+            break
+    lines.append(codeowners_marker)
+    for gitmod in sorted(git_modules, key=lambda gitmod: gitmod.path):
+        for location in codeowners_locations:
+            content = read_committed_file(f"{gitmod.path}/{location}")
+            if content is not None:
+                lines.append(f"# From {gitmod.path}/{location}")
+                lines += [
+                    prefix_codeowners_line(line, str(gitmod.path))
+                    for line in content.splitlines()
+                ]
+                break
+    output = "".join(line + "\n" for line in lines)
+    if args.output is None:
+        sys.stdout.write(output)
+    else:
+        args.output.write_text(output, encoding="utf-8")
+    return 0
+
+
+def main_apply_patch(args) -> int:
+    monorepo = MonoRepo(args.cwd)
+    git_modules = get_gitmodules_info(
+        LocalFileConfigLoader(monorepo.path / ".gitmodules", allow_missing=True),
+        monorepo.get_toprepo_fetch_url(),
+    )
+    subdir = PurePosixPath(args.path)
+    if subdir not in {gitmod.path for gitmod in git_modules}:
+        print(f"ERROR: {subdir} is not a submodule path in .gitmodules")
+        return 1
+    # Let git rewrite the paths, it also handles renames and binary patches.
+    if args.no_commit:
+        git_args = ["apply", "--index"]
+    else:
+        git_args = ["am"]
+    git_args.append(f"--directory={subdir}")
+    result = log_run_git(
+        monorepo.path,
+        git_args + [str(patch.absolute()) for patch in args.patches],
+        check=False,
+    )
+    assert result is not None
+    if result.returncode != 0:
+        print(f"ERROR: Failed to apply the patches onto {subdir}")
+        return 1
+    return 0
+
+
+def format_graphviz(commits: List[Tuple[CommitHash, List[CommitHash], bytes]]) -> str:
+    """Renders mono commits and their original commits as a DOT graph.
+
+    Args:
+        commits: The mono commit hash, its parents and its message.
+
+    First parent edges are solid, while additional parents, e.g. from injected
+    subrepo history, are dashed. Each original commit from the annotations is
+    drawn as a box with a dotted edge from the mono commit.
+    """
+
+    def quote(text: str) -> str:
+        return '"' + text.replace("\\", "\\\\").replace('"', '\\"') + '"'
+
+    lines = ["digraph toprepo {", "  rankdir=BT;"]
+    original_nodes: Set[str] = set()
+    for commit_hash, parents, message in commits:
+        mono_id = commit_hash.decode("utf-8")
+        subject = message.split(b"\n", 1)[0].decode("utf-8", errors="replace")
+        label = mono_id[:12] + " " + subject
+        lines.append(f"  {quote(mono_id)} [label={quote(label)}];")
+        for idx, parent in enumerate(parents):
+            parent_id = parent.decode("utf-8")
+            style = "" if idx == 0 else " [style=dashed]"
+            lines.append(f"  {quote(mono_id)} -> {quote(parent_id)}{style};")
+        for subdir, orig_hash in parse_annotations(message):
+            orig_id = subdir.decode("utf-8") + " " + orig_hash.decode("utf-8")
+            if orig_id not in original_nodes:
+                original_nodes.add(orig_id)
+                label = subdir.decode("utf-8") + " " + orig_hash.decode("utf-8")[:12]
+                lines.append(f"  {quote(orig_id)} [shape=box, label={quote(label)}];")
+            lines.append(f"  {quote(mono_id)} -> {quote(orig_id)} [style=dotted];")
+    lines.append("}")
+    return "".join(line + "\n" for line in lines)
+
+
+def format_original_log(commits: List[Tuple[CommitHash, bytes]], original: bool) -> str:
+    """Lists mono commits, optionally with the commits they were expanded from.
+
+    Args:
+        commits: The mono commit hash and its message.
+        original: Whether to list the original commits from the annotations,
+            indented below each mono commit as "<subdir> <commit-hash>".
+    """
+    lines: List[str] = []
+    for commit_hash, message in commits:
+        subject = message.split(b"\n", 1)[0].decode("utf-8", errors="replace")
+        lines.append(f"{commit_hash.decode('utf-8')} {subject}")
+        if original:
+            for subdir, orig_hash in parse_annotations(message):
+                lines.append(
+                    f"    {subdir.decode('utf-8')} {orig_hash.decode('utf-8')}"
+                )
+    return "".join(line + "\n" for line in lines)
+
+
+def format_notices(
+    mono_commit: str,
+    entries: List[Tuple[PurePosixPath, RepoConfig, Optional[CommitHash]]],
+) -> str:
+    """Formats a third-party notices document in Markdown.
+
+    Args:
+        entries: The path, repository and commit of each expanded submodule.
+            Only the first entry for each path is listed.
+    """
+    lines = [
+        "# Third-party notices",
+        "",
+        f"Sub repositories included in monorepo commit {mono_commit}.",
+    ]
+    listed_paths: Set[PurePosixPath] = set()
+    for path, repo_config, commit_hash in entries:
+        if path in listed_paths:
+            continue
+        listed_paths.add(path)
+        lines += ["", f"## {repo_config.name}", ""]
+        lines.append(f"* Path: `{path}`")
+        lines.append(f"* Source: {repo_config.provenance_url or repo_config.fetch_url}")
+        if commit_hash is not None:
+            lines.append(f"* Commit: {commit_hash.decode('utf-8')}")
+        lines.append(f"* License: {repo_config.license or 'unknown'}")
+    return "\n".join(lines) + "\n"
+
+
+def main_notices(args) -> int:
+    monorepo = MonoRepo(args.cwd)
+    config_dict = ConfigAccumulator(monorepo, online=False).try_load_main_config()
+    if config_dict is None:
+        return 1
+    config = Config.try_create(config_dict)
+    if config is None:
+        return 1
+    mono_commit = subprocess.check_output(
+        ["git", "-C", str(monorepo.path), "rev-parse", "--verify"]
+        + [f"{args.revision}^{{commit}}"],
+        text=True,
+    ).strip()
+    # Expanded submodules are directories, the others are kept as gitlinks.
+    ls_tree = subprocess.check_output(
+        ["git", "-C", str(monorepo.path), "ls-tree", "-r", "-d", "-z", mono_commit],
+    )
+    tree_paths: Set[PurePosixPath] = set()
+    for entry in ls_tree.split(b"\0"):
+        if entry.startswith(b"040000 "):
+            _, path = entry.split(b"\t", 1)
+            tree_paths.add(PurePosixPath(path.decode("utf-8")))
+    gitmodules = get_gitmodules_info(
+        GitRemoteConfigLoader(
+            url="",
+            remote_ref="",
+            filename=PurePosixPath(".gitmodules"),
+            local_repo=monorepo,
+            local_ref=mono_commit,
+        ),
+        monorepo.get_toprepo_fetch_url(),
+        config.gitmodules_conflicts,
+    )
+    entries: List[Tuple[PurePosixPath, RepoConfig, Optional[CommitHash]]] = []
+    unlicensed_repos: Set[RepoName] = set()
+    for gitmod in sorted(gitmodules, key=lambda gitmod: gitmod.path):
+        if gitmod.path not in tree_paths:
+            continue
+        # Several repositories can share a URL, list the path once.
+        repo_config = next(
+            (
+                repo_config
+                for repo_config in config.raw_url_to_repos.get(gitmod.raw_url, [])
+                if repo_config.enabled
+            ),
+            None,
+        )
+        if repo_config is None:
+            continue
+        # The last commit updating the path tells which commit it is at.
+        message = subprocess.check_output(
+            ["git", "-C", str(monorepo.path), "log", "-1", "--format=%B"]
+            + [mono_commit, "--", str(gitmod.path)],
+        )
+        subdir = str(gitmod.path).encode("utf-8")
+        commit_hashes = [
+            commit_hash
+            for annotated_subdir, commit_hash in parse_annotations(message)
+            if annotated_subdir == subdir
+        ]
+        entries.append(
+            (gitmod.path, repo_config, commit_hashes[-1] if commit_hashes else None)
+        )
+        if repo_config.license is None and repo_config.name not in unlicensed_repos:
+            unlicensed_repos.add(repo_config.name)
+            print(
+                f"WARNING: toprepo.repo.{repo_config.name}.license is not set",
+                file=sys.stderr,
+            )
+    notices = format_notices(mono_commit, entries)
+    if args.output is None:
+        sys.stdout.write(notices)
+    else:
+        args.output.write_text(notices, encoding="utf-8")
+    return 0
+
+
+def main_dump(args) -> int:
+    monorepo = MonoRepo(args.cwd)
+    # <hash> SP <parents> NUL <message> NUL
+    log_output = subprocess.check_output(
+        ["git", "-C", str(monorepo.path), "log", "-z", "--format=%H %P%x00%B"]
+        + args.revisions
+        + ["--"],
+    )
+    fields = log_output.split(b"\0")
+    commits: List[Tuple[CommitHash, List[CommitHash], bytes]] = []
+    for header, message in zip(fields[0::2], fields[1::2]):
+        commit_hash, *parents = header.strip(b"\n").split(b" ")
+        commits.append((commit_hash, [pid for pid in parents if pid != b""], message))
+    if args.format == "graphviz":
+        sys.stdout.write(format_graphviz(commits))
+    else:
+        assert False, f"Unknown format {args.format}"
+    return 0
+
+
+def main_log(args) -> int:
+    monorepo = MonoRepo(args.cwd)
+    # <hash> NUL <message> NUL
+    log_output = subprocess.check_output(
+        ["git", "-C", str(monorepo.path), "log", "-z", "--format=%H%x00%B"]
+        + (args.revisions or ["HEAD"])
+        + ["--"],
+    )
+    fields = log_output.split(b"\0")
+    commits = [
+        (commit_hash.strip(b"\n"), message)
+        for commit_hash, message in zip(fields[0::2], fields[1::2])
+    ]
+    sys.stdout.write(format_original_log(commits, args.original))
+    return 0
+
+
+def get_gitmodules_urls(repo: Repo, rev: str) -> Set[RawUrl]:
+    """Returns the raw submodule URLs in .gitmodules at a revision."""
+    result = subprocess.run(
+        ["git", "-C", str(repo.path), "config", "--blob", f"{rev}:.gitmodules"]
+        + ["--get-regexp", r"^submodule\..*\.url$"],
+        check=False,
+        stdout=subprocess.PIPE,
+        stderr=subprocess.DEVNULL,
+        text=True,
+    )
+    # Exit code 1 means no URLs, otherwise .gitmodules is missing or broken.
+    urls: Set[RawUrl] = set()
+    for line in result.stdout.splitlines():
+        _, url = line.split(" ", 1)
+        urls.add(url)
+    return urls
+
+
+def summarize_url_references(
+    history: List[Tuple[CommitHash, Set[RawUrl]]]
+) -> Dict[RawUrl, Tuple[CommitHash, CommitHash]]:
+    """Finds the first and last commit referencing each URL.
+
+    Args:
+        history: The commits changing .gitmodules and their URLs,
+            ordered from newest to oldest.
+
+    Returns:
+        A map from URL to the oldest and the newest commit referencing it.
+    """
+    ret: Dict[RawUrl, Tuple[CommitHash, CommitHash]] = {}
+    for commit_hash, urls in history:
+        for url in urls:
+            last = ret[url][1] if url in ret else commit_hash
+            ret[url] = (commit_hash, last)
+    return ret
+
+
+def main_annotate_config(args) -> int:
+    monorepo = MonoRepo(args.cwd)
+    config_dict = ConfigAccumulator(monorepo, online=False).try_load_main_config()
     if config_dict is None:
         return 1
     config = Config.try_create(config_dict)
     if config is None:
         return 1
     toprepo = TopRepo.from_config(monorepo.get_toprepo_dir(), config)
-    repo_fetcher = RepoFetcher(monorepo)
 
-    git_modules = get_gitmodules_info(
-        LocalFileConfigLoader(monorepo.path / ".gitmodules", allow_missing=True),
-        monorepo.get_toprepo_fetch_url(),
+    log_output = subprocess.check_output(
+        ["git", "-C", str(toprepo.path), "log", "--all", "--format=%H"]
+        + ["--", ".gitmodules"],
+        text=True,
     )
-    maybe = remote_to_repo(args.remote, git_modules, config)
-    if maybe is None:
-        return 1
-    remote_name, git_module = maybe
+    history = [
+        (commit_hash.encode("utf-8"), get_gitmodules_urls(toprepo, commit_hash))
+        for commit_hash in log_output.split()
+    ]
+    url_to_commits = summarize_url_references(history)
+    tips = subprocess.check_output(
+        ["git", "-C", str(toprepo.path), "for-each-ref", "--format=%(objectname)"]
+        + ["refs/heads/", "refs/tags/"],
+        text=True,
+    ).split()
+    urls_at_tips: Set[RawUrl] = set()
+    for tip in sorted(set(tips)):
+        urls_at_tips.update(get_gitmodules_urls(toprepo, tip))
+
+    for repo_config in sorted(config.repos, key=lambda repo: repo.name):
+        print(f"toprepo.repo.{repo_config.name}")
+        for url in repo_config.raw_urls:
+            commits = url_to_commits.get(url)
+            if commits is None:
+                print(f"  {url}: not referenced in the history")
+                continue
+            first, last = (commit_hash.decode("utf-8") for commit_hash in commits)
+            status = (
+                "in use at a branch or tag"
+                if url in urls_at_tips
+                else "unused at all branches and tags"
+            )
+            print(f"  {url}: first {first[:12]}, last {last[:12]}, {status}")
+    return 0
 
-    if remote_name == TopRepo.name:
-        topexpander = TopRepoExpander(monorepo, toprepo, config)
-        repo_to_fetch: Union[TopRepo, SubRepo] = toprepo
-    else:
-        assert (
-            git_module
-        ), f"git module information is required for remote: {remote_name}"
-        subexpander = SubrepoCommitExpander(monorepo)
-        for subrepo_config in config.repos:
-            if subrepo_config.name == remote_name:
-                repo_to_fetch = SubRepo(
-                    subrepo_config,
-                    monorepo.get_subrepo_dir(subrepo_config.name),
-                )
-                subdir = git_module.path.as_posix().encode("utf-8")
-                break
-        else:
-            print(f"ERROR: Could not resolve the remote {args.remote}")
-            return 1
 
-    ref_args: List[str]
-    if args.ref is None:
-        # Just fetch everything in that repo and do standard filtering.
-        repo_fetcher.fetch_repo(repo_to_fetch)
-        if args.do_filter:
-            if not topexpander.expand_toprepo(
-                top_refs=["--all"],
-                allow_fetching=True,
-                abort_on_missing=args.abort_on_missing,
-            ):
-                return 1
-        else:
-            print("Skipped expanding the toprepo into the monorepo.")
-    else:
-        # Fetch ref to refs/toprepo/fetch-head instead of FETCH_HEAD.
-        # Then there is no need for extra args to git-fetch or git-filter-repo
-        # to pick up FETCH_HEAD.
-        ref_args = [f"+{args.ref}:refs/toprepo/fetch-head"]
-        repo_fetcher.fetch_repo(repo_to_fetch, ref_args)
-        if args.do_filter:
-            mono_fetch_head_ref = "refs/toprepo/fetch-head"
-            if repo_to_fetch.is_top:
-                # Special handling of toprepo/fetch-head in the refname_callback.
-                top_fetch_head_ref = mono_fetch_head_ref
-                # TODO: Only expand top_fetch_head_ref, i.e. remove "--all".
-                # Currently, omitting --all gives different result.
-                if not topexpander.expand_toprepo(
-                    top_refs=[top_fetch_head_ref, "--all"],
-                    allow_fetching=True,
-                    abort_on_missing=args.abort_on_missing,
-                ):
-                    return 1
-            else:
-                subrepo_ref = f"refs/repos/{repo_to_fetch.name}/toprepo/fetch-head"
-                if not subexpander.expand_subrepo_refs(
-                    subdir, subrepo_ref, dest_ref=mono_fetch_head_ref
-                ):
-                    return 1
-            # Update FETCH_HEAD.
-            subprocess.check_call(
-                ["git", "-C", str(monorepo.path)]
-                + ["update-ref", "FETCH_HEAD", mono_fetch_head_ref],
-            )
-            print("Updated FETCH_HEAD")
+def expand_alias(
+    alias: str, args: List[str], commands: Iterable[str]
+) -> List[Tuple[str, List[str]]]:
+    """Splits a toprepo.alias.<name> value into steps to run.
+
+    The steps are separated by `&&` and `{0}`, `{1}` etc. are replaced by the
+    arguments. Steps starting with a git-toprepo command run git-toprepo,
+    other steps run git.
+
+    Returns:
+        A list of ("toprepo" or "git", arguments) for the steps.
+    """
+
+    def substitute(match: re.Match) -> str:
+        idx = int(match.group(1))
+        if idx >= len(args):
+            raise ValueError(f"Missing argument {{{idx}}}")
+        return args[idx]
+
+    step_words: List[List[str]] = [[]]
+    for word in shlex.split(alias):
+        if word == "&&":
+            step_words.append([])
         else:
-            print(
-                "Fetched refs/toprepo/fetch-head but skipped creating a "
-                + "monorepo FETCH_HEAD."
-            )
-    return 0
+            step_words[-1].append(re.sub(r"\{(\d+)\}", substitute, word))
+    if any(len(words) == 0 for words in step_words):
+        raise ValueError("Empty step")
+    return [
+        ("toprepo" if words[0] in commands else "git", words) for words in step_words
+    ]
 
 
-def main_push(args) -> int:
+def main_alias(args) -> int:
     monorepo = MonoRepo(args.cwd)
-    config_dict = ConfigAccumulator(monorepo, online=True).try_load_main_config()
+    config_dict = ConfigAccumulator(monorepo, online=False).try_load_main_config()
     if config_dict is None:
         return 1
-    config = Config.try_create(config_dict)
-    if config is None:
+    alias = config_dict.get(f"toprepo.alias.{args.alias.lower()}", [None])[-1]
+    if alias is None:
+        print(f"ERROR: {args.alias!r} is neither a command nor a toprepo.alias")
         return 1
-    toprepo = TopRepo.from_config(monorepo.get_toprepo_dir(), config)
-
-    splitter = PushSplitter(monorepo, toprepo, config)
-
-    refspec: PushRefSpec = args.local_and_remote_ref
     try:
-        push_instructions = splitter.split_commits(refspec.local_ref)
-    except PushSplitError as err:
-        print(f"\nERROR: {err}")
+        steps = expand_alias(alias, args.alias_args, args.commands)
+    except ValueError as err:
+        print(f"ERROR: Invalid toprepo.alias.{args.alias} {alias!r}: {err}")
         return 1
+    for kind, step_args in steps:
+        if kind == "toprepo":
+            returncode = main([args.prog, "-C", str(args.cwd)] + step_args)
+        else:
+            returncode = subprocess.run(
+                ["git", "-C", str(args.cwd)] + step_args, check=False
+            ).returncode
+        if returncode != 0:
+            return returncode
+    return 0
 
-    # Push to each subrepo.
-    repos_to_push = {push.repo.name: push.repo for push in push_instructions}
-    for repo in repos_to_push.values():
-        log_run_git(
-            monorepo.path,
-            ["push", "--quiet", "--force", str(repo.path.absolute())]
-            + [f"refs/repos/{repo.name}/toprepo/push:refs/toprepo/push"],
-            log_command=False,
-        )
-
-    # Sort per branch and remove unnecessary pushes.
-    repo_to_pushes: DefaultDict[RepoName, List[PushInstruction]] = defaultdict(list)
-    for new_push in push_instructions:
-        push_list = repo_to_pushes[new_push.repo.name]
-        if len(push_list) != 0 and push_list[-1].same_but_commit(new_push):
-            push_list.pop()
-        push_list.append(new_push)
 
-    # Push per repo
-    for repo_name, push_list in repo_to_pushes.items():
-        for push in push_list:
-            push_rev = push.commit_hash.decode("utf-8")
-            log_run_git(
-                push.repo.path,
-                ["push", "--quiet", push.repo.config.push_url]
-                + [f"{push_rev}:{refspec.remote_ref}"]
-                + push.extra_args,
-                log_command=True,
-                dry_run=args.dry_run,
-                check=False,
-            )
-    return 0
+def find_command_index(argv: List[str]) -> Optional[int]:
+    """Returns the index of the command in argv, after the global options."""
+    idx = 1
+    while idx < len(argv):
+        if argv[idx] == "-C":
+            idx += 2
+        elif argv[idx].startswith("-"):
+            idx += 1
+        else:
+            return idx
+    return None
 
 
 def _parse_arguments(argv: List[str]):
@@ -2539,8 +6381,10 @@ def _parse_arguments(argv: List[str]):
         "-C",
         dest="cwd",
         type=Path,
-        default=Path.cwd(),
-        help="Working directory, defaults to '.'.",
+        default=Path("."),
+        help="""\
+            Working directory to find the monorepo from, defaults to '.'.
+            Other path arguments are still relative to the current directory.""",
     )
     parser.set_defaults(func=None)
     subparsers = parser.add_subparsers()
@@ -2571,7 +6415,8 @@ def _parse_arguments(argv: List[str]):
     config_parser = subparsers.add_parser(
         "config",
         description="""\
-            Reads the mono repository configuration.
+            Reads the mono repository configuration, or enables and
+            disables sub repositories.
         """,
     )
     config_parser.set_defaults(func=main_config)
@@ -2589,6 +6434,20 @@ def _parse_arguments(argv: List[str]):
         action="store_true",
         help="List all configurations.",
     )
+    config_key_group.add_argument(
+        "--enable",
+        metavar="REPO",
+        help="""\
+            Expand the sub repository REPO for the current role, by adding
+            +REPO to toprepo.role.<role>.repos in .git/config.""",
+    )
+    config_key_group.add_argument(
+        "--disable",
+        metavar="REPO",
+        help="""\
+            Keep the sub repository REPO as a submodule for the current role,
+            by adding -REPO to toprepo.role.<role>.repos in .git/config.""",
+    )
     config_key_group.add_argument(
         "key",
         type=str,
@@ -2621,6 +6480,28 @@ def _parse_arguments(argv: List[str]):
         help="Disallow fetching submodules, use existing information only.",
     )
 
+    expand_range_parser = subparsers.add_parser(
+        "expand-range",
+        description="""\
+            Makes sure that every top repository commit in a range has been
+            expanded into the monorepo, even if no branch points to them,
+            e.g. to run git-bisect over suspected submodule bumps.""",
+    )
+    expand_range_parser.set_defaults(func=main_expand_range)
+    expand_range_parser.add_argument(
+        "--offline",
+        action="store_false",
+        dest="online",
+        help="Disallow fetching submodules, use existing information only.",
+    )
+    expand_range_parser.add_argument(
+        "range",
+        metavar="old-top..new-top",
+        help="""\
+            The top repository commits, where old-top is an ancestor of
+            new-top. The commits must already be fetched.""",
+    )
+
     fetch_parser = subparsers.add_parser(
         "fetch",
         description="""\
@@ -2638,6 +6519,54 @@ def _parse_arguments(argv: List[str]):
         dest="do_filter",
         help="Fetch from the remote but skip monorepo filtering.",
     )
+    fetch_parser.add_argument(
+        "--apply-redirects",
+        action="store_true",
+        help="""\
+            When the server redirects to another URL, e.g. after a project
+            rename, configure the monorepo to fetch from the new URL while
+            still accepting the old URL in the history.""",
+    )
+    fetch_parser.add_argument(
+        "--no-tags",
+        action="store_false",
+        dest="fetch_tags",
+        help="""\
+            Don't fetch any tags from the top repository or the sub repos,
+            overriding toprepo.repo.<name>.fetchTags.""",
+    )
+    fetch_parser.add_argument(
+        "--recurse-submodules",
+        action="store_true",
+        help="""\
+            After fetching, run 'git submodule update --init' for the
+            submodules in the worktree that are not expanded,
+            i.e. the ones not selected by toprepo.role.<role>.repos.""",
+    )
+    fetch_parser.add_argument(
+        "--watch",
+        action="store_true",
+        help="""\
+            Keep running and fetch again when the refs of the top repository
+            change, checking every --interval seconds. Stop with Ctrl-C.""",
+    )
+    fetch_parser.add_argument(
+        "--interval",
+        type=float,
+        default=60,
+        help="Seconds between the checks for --watch. Defaults to 60.",
+    )
+    fetch_parser.add_argument(
+        "--stdin",
+        action="store_true",
+        help="""\
+            Read '<remote> <ref>' pairs from stdin, one per line,
+            instead of a single remote and ref on the command line.
+            All refs are fetched before a single filtering pass and
+            each result is stored in
+            <toprepo.fetchRefPrefix><time>-<pid>/<line-index>
+            and listed in FETCH_HEAD.""",
+    )
     fetch_parser.add_argument(
         "remote",
         type=str,
@@ -2647,7 +6576,11 @@ def _parse_arguments(argv: List[str]):
             The URL or a submodule path to fetch from.
             Will fetch from the top repository remote
             if 'origin', '.' or '' is specified.
-            Defaults to 'origin'.""",
+            Defaults to 'origin'.
+
+            When fetching a ref with 'origin' from a directory inside an
+            expanded sub repository, that sub repository is fetched from.
+            Use '.' to fetch from the top repository.""",
     )
     fetch_parser.add_argument(
         "ref",
@@ -2656,10 +6589,22 @@ def _parse_arguments(argv: List[str]):
         help="""\
             The 'refspec' to be fetched from the specified remote.
             If a single ref is specified,
-            FETCH_HEAD will be updated accordingly.""",
+            FETCH_HEAD will be updated accordingly.
+            A '*' matches the refs of the remote, e.g.
+            'refs/heads/*:refs/remotes/mirror/*', and the expanded commits
+            are stored in the destination refs in the monorepo.""",
     )
 
-    for subparser in [fetch_parser, refilter_parser]:
+    for subparser in [fetch_parser, refilter_parser, expand_range_parser]:
+        subparser.add_argument(
+            "-j",
+            "--jobs",
+            type=int,
+            default=1,
+            help="""\
+                How many sub repositories to load in parallel, e.g. the
+                number of CPUs. Defaults to 1.""",
+        )
         subparser.add_argument(
             "--abort-on-unexpected-missing-commits",
             dest="abort_on_missing",
@@ -2687,42 +6632,442 @@ def _parse_arguments(argv: List[str]):
             Use this option to push to manually push a different repository
             than the default configured 'origin'.""",
     )
+    push_ready_group = push_parser.add_mutually_exclusive_group()
+    push_ready_group.add_argument(
+        "--wip",
+        action="store_true",
+        help="Mark the changes as work in progress in Gerrit, '-o wip'.",
+    )
+    push_ready_group.add_argument(
+        "--ready",
+        action="store_true",
+        help="Mark the changes as ready for review in Gerrit, '-o ready'.",
+    )
+    push_parser.add_argument(
+        "--create-branch",
+        action="store_true",
+        help="""\
+            Allow pushing to refs/heads/<branch> that does not yet exist
+            upstream. Repositories can deny this with
+            toprepo.repo.<name>.createBranches = false, which is checked
+            before anything is pushed. A dry run skips the check.""",
+    )
+    push_parser.add_argument(
+        "--bump-top",
+        action="store_true",
+        help="""\
+            For commits changing multiple sub repositories but not the top
+            repository, also push a top repository commit that updates the
+            submodules to the pushed commits, in the same topic.""",
+    )
+    push_parser.add_argument(
+        "--private",
+        action="store_true",
+        help="Mark the changes as private in Gerrit, '-o private'.",
+    )
+    push_parser.add_argument(
+        "--hashtag",
+        dest="hashtags",
+        action="append",
+        default=[],
+        help="""\
+            Add a hashtag to the changes in Gerrit, '-o hashtag=<hashtag>'.
+            Can be specified multiple times.""",
+    )
     push_parser.add_argument(
         "remote",
         type=str,
         nargs="?",
-        choices=["origin"],
         help="""\
-            Unused placeholder in case the user writes 'origin'
-            on the command line, like with git-push.""",
+            A remote in the monorepo to push the top repository to,
+            defaults to remote.pushDefault. 'origin' and 'top' push to
+            remote.top.pushUrl. Relative sub repository URLs
+            are resolved against the URL of the remote.""",
     )
     push_parser.add_argument(
-        "local_and_remote_ref",
+        "refspecs",
         metavar="local-ref:remote-ref",
         type=PushRefSpec.parse,
+        nargs="+",
         help="""\
-            The refspec describing what to push, just like git-push.
+            The refspecs describing what to push, just like git-push.
+            The monorepo history is split once for all of them.
 
             If a single branch name is specified, it is translated into
-            'refs/heads/<branch>:refs/heads/<branch>'.""",
+            'refs/heads/<branch>:refs/heads/<branch>'. The local side can be
+            any commit, e.g. 'HEAD~2:refs/for/main' from a detached HEAD.""",
+    )
+
+    bump_parser = subparsers.add_parser(
+        "bump",
+        description="""\
+            Creates a top repository commit that bumps the submodules to the
+            upstream commits of changes that have been merged, like Gerrit's
+            superproject subscription would do. The merged upstream commits
+            are found through the Change-Id footers of the local monorepo
+            commits.""",
+    )
+    bump_parser.set_defaults(func=main_bump)
+    bump_parser.add_argument(
+        "--from",
+        dest="mono_rev",
+        default="HEAD",
+        help="""\
+            The monorepo commits whose sub repository changes to bump,
+            compared to the fetched monorepo branches. Defaults to HEAD.""",
+    )
+    bump_parser.add_argument(
+        "--branch",
+        default="main",
+        help="The top repository branch to create the commit on top of.",
+    )
+    bump_parser.add_argument(
+        "--push",
+        dest="push_ref",
+        metavar="REF",
+        help="Push the created commit to this ref in the top repository.",
+    )
+    bump_parser.add_argument(
+        "--dry-run",
+        "-n",
+        action="store_true",
+        help="Write the git-push command instead of executing it.",
+    )
+
+    metrics_parser = subparsers.add_parser(
+        "metrics",
+        description="""\
+            Shows how long the latest expansions of the top repository took,
+            as recorded in .git/toprepo/metrics.json.""",
+    )
+    metrics_parser.set_defaults(func=None)
+    metrics_subparsers = metrics_parser.add_subparsers()
+    metrics_show_parser = metrics_subparsers.add_parser(
+        "show",
+        description="Prints the recorded expansion durations per phase.",
+    )
+    metrics_show_parser.set_defaults(func=main_metrics_show)
+    metrics_show_parser.add_argument(
+        "--limit",
+        type=int,
+        default=20,
+        help="The number of latest expansions to show, defaults to 20.",
+    )
+
+    filter_pending_parser = subparsers.add_parser(
+        "filter-pending",
+        description="""\
+            Expands what was fetched with 'git toprepo fetch --skip-filter'
+            into the monorepo, as the fetch would have done. Fetched refs
+            are listed and written to FETCH_HEAD.""",
+    )
+    filter_pending_parser.set_defaults(func=main_filter_pending)
+
+    status_parser = subparsers.add_parser(
+        "status",
+        description="""\
+            Shows, without fetching, whether each top repository branch has
+            been expanded into the monorepo and, for each configured sub
+            repository, whether it has been fetched and how many of the
+            commits referred to at the top branch tips are missing.""",
+    )
+    status_parser.set_defaults(func=main_status)
+
+    verify_parser = subparsers.add_parser(
+        "verify",
+        description="""\
+            Checks, without fetching, that the expanded monorepo commits match
+            the top and sub repository commits they were expanded from, i.e.
+            that the annotated commits exist, that each expanded subdirectory
+            has the content of the annotated commit and that the top
+            repository refers to it. Suggests 'git toprepo refilter' if not.""",
+    )
+    verify_parser.set_defaults(func=main_verify)
+    verify_parser.add_argument(
+        "revs",
+        nargs="*",
+        help="The monorepo commits to check, default all of origin.",
+    )
+
+    clean_parser = subparsers.add_parser(
+        "clean",
+        description="""\
+            Removes the state that git-toprepo keeps in the monorepo: the
+            top and sub repository clones in .git/repos, .git/toprepo,
+            .git/filter-repo and the refs under refs/repos and refs/toprepo.
+            A later
+            'git toprepo fetch' recreates them.""",
+    )
+    clean_parser.set_defaults(func=main_clean)
+    clean_parser.add_argument(
+        "--dry-run",
+        "-n",
+        action="store_true",
+        help="Only print what would be removed.",
+    )
+    clean_parser.add_argument(
+        "--all",
+        action="store_true",
+        help="""\
+            Also remove the expanded refs/remotes/origin/* and the push URLs
+            configured by 'git toprepo init', leaving only the origin remote
+            of the top repository.""",
+    )
+
+    prune_parser = subparsers.add_parser(
+        "prune",
+        description="""\
+            Removes the refs under refs/repos/<name> and the clone in
+            .git/repos/<name> of sub repositories that are no longer
+            configured or not selected by the role.""",
+    )
+    prune_parser.set_defaults(func=main_prune)
+    prune_parser.add_argument(
+        "--dry-run",
+        "-n",
+        action="store_true",
+        help="Only print what would be removed.",
+    )
+    prune_parser.add_argument(
+        "--gc",
+        action="store_true",
+        help="Run 'git gc' in the monorepo afterwards.",
+    )
+
+    annotate_config_parser = subparsers.add_parser(
+        "annotate-config",
+        description="""\
+            Shows which top repository history requires each URL in the
+            toprepo.repo.<name>.urls configuration. For each URL, the first
+            and the last commit updating .gitmodules with that URL are
+            printed, together with whether any branch or tag still uses it.""",
+    )
+    annotate_config_parser.set_defaults(func=main_annotate_config)
+
+    notices_parser = subparsers.add_parser(
+        "notices",
+        description="""\
+            Generates a third-party notices document in Markdown for the
+            sub repositories expanded in a monorepo commit, using
+            toprepo.repo.<name>.license and toprepo.repo.<name>.provenanceUrl.""",
+    )
+    notices_parser.set_defaults(func=main_notices)
+    notices_parser.add_argument(
+        "-o",
+        "--output",
+        type=Path,
+        help="Write the document to this file instead of stdout.",
+    )
+    notices_parser.add_argument(
+        "revision",
+        nargs="?",
+        default="HEAD",
+        help="The monorepo commit to list the sub repositories of, default HEAD.",
+    )
+
+    log_parser = subparsers.add_parser(
+        "log",
+        description="""\
+            Lists monorepo commits, one per line. With --original, the top
+            and sub repository commits each one was expanded from are listed
+            below it, e.g. to find the upstream commit when bisecting.""",
+    )
+    log_parser.set_defaults(func=main_log)
+    log_parser.add_argument(
+        "--original",
+        action="store_true",
+        help="""\
+            List the original commits as '<subdir> <commit-hash>' below each
+            monorepo commit, where the top repository is called '<top>'.""",
+    )
+    log_parser.add_argument(
+        "revisions",
+        nargs="*",
+        help="The monorepo commits to list, as for git-log, default HEAD.",
+    )
+
+    dump_parser = subparsers.add_parser(
+        "dump",
+        description="""\
+            Dumps monorepo commits together with the top and sub repository
+            commits they were expanded from, for debugging the expansion.""",
+    )
+    dump_parser.set_defaults(func=main_dump)
+    dump_parser.add_argument(
+        "--format",
+        choices=["graphviz"],
+        default="graphviz",
+        help="""\
+            The output format. 'graphviz' writes a DOT graph where extra
+            parents, e.g. from injected sub repository history, are dashed.""",
+    )
+    dump_parser.add_argument(
+        "revisions",
+        nargs="+",
+        help="The monorepo commits to dump, as for git-log, e.g. 'HEAD~10..HEAD'.",
+    )
+
+    apply_patch_parser = subparsers.add_parser(
+        "apply-patch",
+        description="""\
+            Applies patches made against a sub repository, e.g. from
+            git-format-patch upstream, onto the monorepo by prefixing the
+            paths with where the sub repository is mounted. Each patch in an
+            mbox becomes a monorepo commit, like git-am.""",
+    )
+    apply_patch_parser.set_defaults(func=main_apply_patch)
+    apply_patch_parser.add_argument(
+        "path",
+        help="The path of the submodule in the monorepo that the patches are for.",
+    )
+    apply_patch_parser.add_argument(
+        "patches",
+        nargs="+",
+        type=Path,
+        help="The patch or mbox files to apply.",
+    )
+    apply_patch_parser.add_argument(
+        "--no-commit",
+        action="store_true",
+        help="Apply to the worktree and index without committing, like git-apply.",
+    )
+
+    info_parser = subparsers.add_parser(
+        "info",
+        description="""\
+            Prints where the refs of each repository are mirrored in the
+            monorepo, refs/repos/<repo-name>/ where the top repository is
+            called 'top'. The mapping is stable for use by other tools.""",
+    )
+    info_parser.set_defaults(func=main_info)
+    info_parser.add_argument(
+        "--value",
+        choices=["ref-prefix", "repo"],
+        help="""\
+            Print a single value. 'ref-prefix' is the ref prefix of --repo and
+            'repo' is the repository name and original ref of --ref.""",
+    )
+    info_parser.add_argument(
+        "--repo",
+        default=TopRepo.name,
+        help="The repository name for --value ref-prefix, default 'top'.",
+    )
+    info_parser.add_argument(
+        "--ref",
+        help="The monorepo ref for --value repo, e.g. refs/repos/foo/heads/main.",
+    )
+
+    shell_prompt_parser = subparsers.add_parser(
+        "shell-prompt",
+        description="""\
+            Prints the monorepo state compactly for a shell prompt, e.g.
+            'toprepo pending:2 stale', or nothing outside of a monorepo.
+            Only files in the git directory are read, no git commands are
+            run, to keep it fast enough for every prompt.""",
+    )
+    shell_prompt_parser.set_defaults(func=main_shell_prompt)
+
+    codeowners_parser = subparsers.add_parser(
+        "codeowners",
+        description="""\
+            Generates a CODEOWNERS file for the monorepo from the CODEOWNERS
+            files in the top repository and the root of each sub repository,
+            as committed in HEAD.
+            The patterns from the sub repositories are prefixed with their
+            path in the monorepo. Gerrit OWNERS files need no conversion,
+            as they already apply to their own directory.""",
+    )
+    codeowners_parser.set_defaults(func=main_codeowners)
+    codeowners_parser.add_argument(
+        "--output",
+        "-o",
+        type=Path,
+        help="Where to write the CODEOWNERS file, defaults to stdout.",
     )
 
+    # Unknown commands are looked up in toprepo.alias.<name>.
+    command_idx = find_command_index(argv)
+    if command_idx is not None and argv[command_idx] not in subparsers.choices:
+        args = parser.parse_args(argv[1:command_idx])
+        args.func = main_alias
+        args.alias = argv[command_idx]
+        args.alias_args = argv[command_idx + 1 :]
+        args.commands = set(subparsers.choices)
+        args.prog = argv[0]
+        absolutize_path_args(args)
+        args.cwd = try_relative_path(args.cwd)
+        return args
+
     args = parser.parse_args(argv[1:])
     if args.func is None:
         parser.print_help()
         parser.exit(status=2)
+    absolutize_path_args(args)
     args.cwd = try_relative_path(args.cwd)
     return args
 
 
+def absolutize_path_args(args: argparse.Namespace) -> None:
+    """Resolves all path arguments relative to the current directory, once,
+    so that they do not depend on -C or on where the code paths run git."""
+    for name, value in vars(args).items():
+        if isinstance(value, Path):
+            setattr(args, name, value.absolute())
+        elif isinstance(value, list) and all(isinstance(v, Path) for v in value):
+            setattr(args, name, [v.absolute() for v in value])
+    repository = getattr(args, "repository", None)
+    if isinstance(repository, str) and Path(repository).is_dir():
+        # A local top repository is fetched from within .git/repos/top.
+        args.repository = str(Path(repository).absolute())
+
+
+minimum_git_version = (2, 22, 0)
+"""git-filter-repo requires `git diff-tree --combined-all-paths`."""
+
+
+def parse_git_version(version_output: str) -> Optional[Tuple[int, ...]]:
+    """Parses the output from `git --version`, e.g. 'git version 2.39.5'."""
+    match = re.match(r"git version (\d+)\.(\d+)\.(\d+)", version_output)
+    if match is None:
+        return None
+    return tuple(int(part) for part in match.groups())
+
+
+def check_git_version() -> bool:
+    version_output = subprocess.check_output(["git", "--version"], text=True)
+    version = parse_git_version(version_output)
+    if version is None:
+        print(f"WARNING: Could not parse the git version from {version_output!r}")
+        return True
+    if version < minimum_git_version:
+        version_str = ".".join(map(str, version))
+        minimum_str = ".".join(map(str, minimum_git_version))
+        print(
+            f"ERROR: git-toprepo requires git >= {minimum_str}, "
+            + f"but git {version_str} was found"
+        )
+        return False
+    return True
+
+
 def main(argv: List[str] = sys.argv) -> int:
+    global warning_log
     args = _parse_arguments(argv)
+    # Checking the git version runs git, which is too slow for every prompt.
+    if args.func is not main_shell_prompt and not check_git_version():
+        return 1
+    # Aliases call main() again for each step, restore the outer log afterwards.
+    outer_warning_log = warning_log
+    warning_log = WarningLog()
     try:
         returncode = args.func(args=args)
     except subprocess.CalledProcessError as err:
         cmdline = subprocess.list2cmdline(err.cmd)
         print(f"\rFailed to call  {cmdline}")
         raise
+    finally:
+        warning_log.flush()
+        warning_log = outer_warning_log
     assert isinstance(returncode, int), returncode
     return returncode
 
@@ -1,7 +1,11 @@
 #!/usr/bin/env python3
 
+import json
 import os
+import shlex
 import subprocess
+import sys
+import time
 from pathlib import Path, PurePosixPath
 
 import pytest
@@ -9,6 +13,52 @@ import pytest
 import git_toprepo
 
 
+@pytest.fixture(autouse=True)
+def hermetic_git(monkeypatch, tmp_path_factory):
+    """Runs git independently of the host configuration.
+
+    Set GIT_TOPREPO_TEST_GIT to the git binary to use, e.g. in a nix build.
+    """
+    git_binary = os.environ.get("GIT_TOPREPO_TEST_GIT")
+    if git_binary:
+        git_dir = str(Path(git_binary).parent)
+        monkeypatch.setenv("PATH", git_dir + os.pathsep + os.environ.get("PATH", ""))
+    # Older git versions without GIT_CONFIG_GLOBAL read $HOME/.gitconfig.
+    monkeypatch.setenv("HOME", str(tmp_path_factory.mktemp("home")))
+    monkeypatch.setenv("GIT_CONFIG_GLOBAL", os.devnull)
+    monkeypatch.setenv("GIT_CONFIG_NOSYSTEM", "1")
+    monkeypatch.setenv("GIT_DEFAULT_HASH", "sha1")
+
+
+def make_repo_config(name: str, **overrides) -> git_toprepo.RepoConfig:
+    """Returns the RepoConfig of an enabled repository at ssh://server/<name>."""
+    fields = {
+        "name": name,
+        "enabled": True,
+        "raw_urls": [f"../{name}"],
+        "fetch_url": f"ssh://server/{name}",
+        "fetch_args": [],
+        "push_url": f"ssh://server/{name}",
+    }
+    fields.update(overrides)
+    return git_toprepo.RepoConfig(**fields)
+
+
+def parse_sub_repo_config(
+    repo_config_dict: git_toprepo.ConfigDict,
+    parent_fetch_url: str = "ssh://server/top",
+    parent_push_url: str = "ssh://server/top",
+) -> git_toprepo.RepoConfig:
+    """Parses repo_config_dict as toprepo.repo.sub.* for the default role."""
+    return git_toprepo.Config.parse_repo_config(
+        "sub",
+        repo_config_dict,
+        ["+.*"],
+        parent_fetch_url=parent_fetch_url,
+        parent_push_url=parent_push_url,
+    )
+
+
 def test_usage_help():
     with pytest.raises(SystemExit) as pytest_err:
         git_toprepo.main(["argv0"])
@@ -16,6 +66,60 @@ def test_usage_help():
     assert pytest_err.value.code == 2
 
 
+def test_path_arguments(tmp_path, monkeypatch):
+    monkeypatch.chdir(tmp_path)
+    (tmp_path / "server").mkdir()
+    args = git_toprepo._parse_arguments(
+        ["argv0", "-C", "mono", "notices", "-o", "notices.md"]
+    )
+    assert args.cwd == Path("mono")
+    assert args.output == tmp_path / "notices.md"
+    args = git_toprepo._parse_arguments(["argv0", "-C", "work", "init", "server"])
+    assert args.repository == str(tmp_path / "server")
+    args = git_toprepo._parse_arguments(["argv0", "init", "ssh://host/server"])
+    assert args.repository == "ssh://host/server"
+
+
+def test_expand_alias():
+    commands = {"fetch", "push"}
+    assert git_toprepo.expand_alias(
+        "fetch origin refs/changes/{0}:FETCH_HEAD && checkout FETCH_HEAD",
+        ["45/12345/2"],
+        commands,
+    ) == [
+        ("toprepo", ["fetch", "origin", "refs/changes/45/12345/2:FETCH_HEAD"]),
+        ("git", ["checkout", "FETCH_HEAD"]),
+    ]
+    # Arguments are never split into more steps.
+    assert git_toprepo.expand_alias("log -1 '{0}'", ["a && b"], commands) == [
+        ("git", ["log", "-1", "a && b"]),
+    ]
+    with pytest.raises(ValueError, match="Missing argument"):
+        git_toprepo.expand_alias("push origin HEAD:{1}", ["x"], commands)
+    with pytest.raises(ValueError, match="Empty step"):
+        git_toprepo.expand_alias("fetch &&", [], commands)
+
+
+def test_find_command_index():
+    assert git_toprepo.find_command_index(["git-toprepo"]) is None
+    assert git_toprepo.find_command_index(["git-toprepo", "fetch"]) == 1
+    assert git_toprepo.find_command_index(["git-toprepo", "-C", "dir", "rv", "1"]) == 3
+    assert git_toprepo.find_command_index(["git-toprepo", "-Cdir", "-h"]) is None
+
+
+def test_parse_git_version():
+    assert git_toprepo.parse_git_version("git version 2.39.5\n") == (2, 39, 5)
+    assert git_toprepo.parse_git_version("git version 2.43.0.windows.1") == (
+        2,
+        43,
+        0,
+    )
+    assert git_toprepo.parse_git_version(
+        "git version 2.39.3 (Apple Git-145)"
+    ) == (2, 39, 3)
+    assert git_toprepo.parse_git_version("hub version 1.2.3") is None
+
+
 def test_push_refspec_parser():
     assert git_toprepo.PushRefSpec.parse("abc:refs/def") == git_toprepo.PushRefSpec(
         local_ref="abc", remote_ref="refs/def"
@@ -30,6 +134,18 @@ def test_push_refspec_parser():
         git_toprepo.PushRefSpec.parse("a:b:c")
 
 
+def test_merge_push_options():
+    assert git_toprepo.merge_push_options(["wip"], ["hashtag=foo"]) == [
+        "wip",
+        "hashtag=foo",
+    ]
+    assert git_toprepo.merge_push_options(["wip", "private"], ["ready"]) == [
+        "private",
+        "ready",
+    ]
+    assert git_toprepo.merge_push_options(["private"], ["private"]) == ["private"]
+
+
 def test_repository_basename():
     assert git_toprepo.repository_basename("https://github.com/org/repo") == "repo"
     assert git_toprepo.repository_basename("https://github.com/org/repo.git") == "repo"
@@ -46,6 +162,472 @@ def test_repository_name():
     assert git_toprepo.repository_name("git://github.com/org/repo") == "org-repo"
     assert git_toprepo.repository_name(".././org/repo") == "org-repo"
     assert git_toprepo.repository_name("abc\\org\\repo") == "abc-org-repo"
+    assert git_toprepo.repository_name("ssh://user@host:29418/a/../b") == "b"
+    assert git_toprepo.repository_name("user@host:org/repo.git") == "org-repo"
+    assert git_toprepo.repository_name("host:repo") == "repo"
+    assert git_toprepo.repository_name("git@[::1]:org/repo") == "org-repo"
+    assert git_toprepo.repository_name("file:///data/repo") == "data-repo"
+
+
+def test_suggest_repository_name():
+    assert (
+        git_toprepo.suggest_repository_name("https://github.com/org/repo", None)
+        == "org-repo"
+    )
+    repo_name_command = (
+        shlex.quote(sys.executable)
+        + " -c 'import sys; print(\"team-\" + sys.argv[-1].split(\"/\")[-1])'"
+    )
+    assert (
+        git_toprepo.suggest_repository_name(
+            "https://github.com/org/repo", repo_name_command
+        )
+        == "team-repo"
+    )
+
+
+def test_format_example_repo_configs():
+    lines = git_toprepo.format_example_repo_configs(
+        [
+            "https://host/Projects/Foo.git",
+            "../../Projects/Foo",
+            "https://host/Projects/Foo/",
+            "https://host/Projects/Bar",
+            "https://other/Projects/Bar",
+            "../Baz",
+        ],
+        "https://host/Top/top",
+        None,
+    )
+    assert lines == [
+        '[toprepo.repo "Baz"]',
+        "\turls = ../Baz",
+        '[toprepo.repo "Projects-Bar"]',
+        "\turls = https://host/Projects/Bar",
+        "\turls = https://other/Projects/Bar",
+        "\tfetchUrl = <fill-in>",
+        '[toprepo.repo "Projects-Foo"]',
+        "\turls = ../../Projects/Foo",
+        "\turls = https://host/Projects/Foo.git",
+        "\turls = https://host/Projects/Foo/",
+        "\tfetchUrl = ../../Projects/Foo",
+    ]
+
+
+def test_get_url_scheme():
+    assert git_toprepo.get_url_scheme("https://github.com/org/repo") == "https"
+    assert git_toprepo.get_url_scheme("SSH://user@host:29418/repo") == "ssh"
+    assert git_toprepo.get_url_scheme("git+ssh://host/repo") == "git+ssh"
+    assert git_toprepo.get_url_scheme("ext::ssh -p 29418 host %S repo") == "ext"
+    assert git_toprepo.get_url_scheme("user@host:org/repo") is None
+    assert git_toprepo.get_url_scheme("../repo") is None
+    assert git_toprepo.get_url_scheme("/data/repo") is None
+
+
+def test_commit_message_to_utf8():
+    assert (
+        git_toprepo.commit_message_to_utf8(b"R\xe4ksm\xf6rg\xe5s\n", b"ISO-8859-1")
+        == "Räksmörgås\n".encode("utf-8")
+    )
+    # Invalid messages and unknown encodings are left to the caller.
+    assert git_toprepo.commit_message_to_utf8(b"a\xffb", b"UTF-8") is None
+    assert git_toprepo.commit_message_to_utf8(b"a\xffb", b"no-such-encoding") is None
+
+
+def test_repo_ref_mapping():
+    assert git_toprepo.repo_ref_prefix("sub") == "refs/repos/sub/"
+    assert git_toprepo.repo_ref("sub", "refs/heads/main") == "refs/repos/sub/heads/main"
+    assert git_toprepo.split_repo_ref("refs/repos/sub/heads/main") == (
+        "sub",
+        "refs/heads/main",
+    )
+    assert git_toprepo.split_repo_ref("refs/repos/sub") is None
+    assert git_toprepo.split_repo_ref("refs/heads/main") is None
+    assert (
+        git_toprepo.strip_repos_ref_prefix("refs/repos/top/tags/v1") == "refs/tags/v1"
+    )
+    assert git_toprepo.strip_repos_ref_prefix("refs/heads/main") == "refs/heads/main"
+
+
+def test_is_stale_fetch_ref():
+    prefix = "refs/toprepo/fetch-heads/"
+    now = 1700000000
+    assert not git_toprepo.is_stale_fetch_ref(
+        "refs/toprepo/fetch-heads/1699999000-123/0", prefix, now
+    )
+    assert git_toprepo.is_stale_fetch_ref(
+        "refs/toprepo/fetch-heads/1600000000-123/0", prefix, now
+    )
+    assert git_toprepo.is_stale_fetch_ref(
+        "refs/repos/sub/toprepo/fetch-heads/1600000000-123/head", prefix, now
+    )
+    # Leftovers from older versions.
+    assert git_toprepo.is_stale_fetch_ref("refs/toprepo/fetch-heads/0", prefix, now)
+    assert not git_toprepo.is_stale_fetch_ref("refs/heads/main", prefix, now)
+    assert not git_toprepo.is_stale_fetch_ref("refs/repos/sub/heads/x", prefix, now)
+
+
+def test_delete_stale_partial_fetches(tmp_path):
+    now = time.time()
+    pack_dir = tmp_path / "objects" / "pack"
+    pack_dir.mkdir(parents=True)
+    old_pack = pack_dir / "tmp_pack_abc"
+    old_pack.write_bytes(b"partial")
+    new_pack = pack_dir / "tmp_pack_def"
+    new_pack.write_bytes(b"in progress")
+    complete_pack = pack_dir / "pack-123.pack"
+    complete_pack.write_bytes(b"done")
+    old_incoming = tmp_path / "objects" / "incoming-xyz"
+    (old_incoming / "ab").mkdir(parents=True)
+    old_time = now - git_toprepo.stale_fetch_refs_seconds - 1
+    for path in [old_pack, complete_pack, old_incoming]:
+        os.utime(path, (old_time, old_time))
+
+    assert git_toprepo.delete_stale_partial_fetches(tmp_path, now) == [
+        old_incoming,
+        old_pack,
+    ]
+    assert not old_pack.exists()
+    assert not old_incoming.exists()
+    assert new_pack.exists()
+    assert complete_pack.exists()
+
+
+def test_prefix_codeowners_line():
+    prefix = git_toprepo.prefix_codeowners_line
+    assert prefix("# Comment", "sub/dir") == "# Comment"
+    assert prefix("", "sub/dir") == ""
+    assert prefix("*  @org/team", "sub/dir") == "/sub/dir/**/* @org/team"
+    assert prefix("*.js @a @b", "sub") == "/sub/**/*.js @a @b"
+    assert prefix("build/ @a", "sub") == "/sub/**/build/ @a"
+    assert prefix("/build/ @a", "sub") == "/sub/build/ @a"
+    assert prefix("docs/api @a", "sub") == "/sub/docs/api @a"
+
+
+def test_tag_signature():
+    signature = b"-----BEGIN PGP SIGNATURE-----\n\nabc\n-----END PGP SIGNATURE-----\n"
+    tag_object = b"object 0123\ntype commit\ntag v1\ntagger T <t@x> 0 +0000\n\nv1\n"
+    assert git_toprepo.try_get_tag_signature(tag_object) is None
+    assert git_toprepo.try_get_tag_signature(tag_object + signature) == signature
+    assert git_toprepo.append_tag_signature(b"v1\n", b"0123", signature) == (
+        b"v1\n\nSignature of the top repository tag 0123, not valid for this tag:\n\n"
+        + b"    -----BEGIN PGP SIGNATURE-----\n\n    abc\n"
+        + b"    -----END PGP SIGNATURE-----\n"
+    )
+
+
+def test_strip_repos_ref_prefix():
+    strip = git_toprepo.strip_repos_ref_prefix
+    assert strip("refs/repos/sub/toprepo/fetch-heads/1-2/0") == (
+        "refs/toprepo/fetch-heads/1-2/0"
+    )
+    assert strip("refs/toprepo/fetch-heads/1-2/0") == "refs/toprepo/fetch-heads/1-2/0"
+
+
+def test_try_get_redirect_url():
+    assert git_toprepo.try_get_redirect_url("From https://a/b\n") is None
+    assert (
+        git_toprepo.try_get_redirect_url(
+            "warning: redirecting to https://new/b.git/\nFrom https://new/b.git/\n"
+        )
+        == "https://new/b.git/"
+    )
+
+
+def test_try_get_change_id_from_message():
+    change_id = "I0123456789abcdef0123456789abcdef01234567"
+    other_id = "Iffffffffffffffffffffffffffffffffffffffff"
+    assert git_toprepo.try_get_change_id_from_message(b"Subject\n") is None
+    assert (
+        git_toprepo.try_get_change_id_from_message(
+            f"Subject\n\nChange-Id: {change_id}\n".encode("utf-8")
+        )
+        == change_id
+    )
+    assert (
+        git_toprepo.try_get_change_id_from_message(
+            f"Subject\n\nChange-Id: {other_id}\nChange-Id: {change_id}\r\n".encode(
+                "utf-8"
+            )
+        )
+        == change_id
+    )
+
+
+def test_add_split_group_footer():
+    group = "12345678-9abc-5def-8123-456789abcdef"
+    assert (
+        git_toprepo.add_split_group_footer(b"Subject\n", group)
+        == b"Subject\n\nToprepo-Split-Group: " + group.encode() + b"\n"
+    )
+    with_footers = git_toprepo.add_split_group_footer(
+        b"Subject\n\nBody\n\nTopic: x\nChange-Id: I01\n", group
+    )
+    assert with_footers == (
+        b"Subject\n\nBody\n\nTopic: x\nChange-Id: I01\n"
+        + b"Toprepo-Split-Group: "
+        + group.encode()
+        + b"\n"
+    )
+    assert git_toprepo.try_get_split_group_from_message(with_footers) == group
+    assert git_toprepo.try_get_split_group_from_message(b"Subject\n") is None
+
+
+def test_repo_change_id():
+    mono_id = "I0123456789abcdef0123456789abcdef01234567"
+    sub_id = git_toprepo.repo_change_id(mono_id, "sub")
+    assert sub_id.startswith("I") and len(sub_id) == 41
+    assert sub_id == git_toprepo.repo_change_id(mono_id, "sub")
+    assert sub_id != git_toprepo.repo_change_id(mono_id, "top")
+
+    message = f"Subject\n\nTopic: x\nChange-Id: {mono_id}\n".encode("utf-8")
+    assert git_toprepo.set_change_id(message, sub_id) == (
+        f"Subject\n\nTopic: x\nChange-Id: {sub_id}\n".encode("utf-8")
+    )
+    assert git_toprepo.set_change_id(b"Subject\n", sub_id) == (
+        f"Subject\n\nChange-Id: {sub_id}\n".encode("utf-8")
+    )
+
+
+def test_mono_commit_tie_break_key():
+    def make_commit(committer_date: bytes, original_id):
+        return git_toprepo.git_filter_repo.Commit(
+            b"refs/heads/main",
+            b"A",
+            b"a@no.domain",
+            committer_date,
+            b"C",
+            b"c@no.domain",
+            committer_date,
+            b"Subject\n",
+            [],
+            [],
+            original_id=original_id,
+        )
+
+    old = make_commit(b"1000 +0000", b"a" * 40)
+    new_b = make_commit(b"2000 +0100", b"b" * 40)
+    new_c = make_commit(b"2000 +0000", b"c" * 40)
+    injected = make_commit(b"2000 +0000", None)
+    commits = [old, new_c, injected, new_b]
+    assert sorted(commits, key=git_toprepo.mono_commit_tie_break_key) == [
+        injected,
+        new_b,
+        new_c,
+        old,
+    ]
+
+
+def test_normalize_identity():
+    normalize = git_toprepo.normalize_identity
+    assert normalize(b"A", b"a@x", b"1000 +0100") == (b"A", b"a@x", b"1000 +0100", [])
+    # Unparsable identity line without an email.
+    assert normalize(b"Old Timer 1000 +0000", None, None) == (
+        b"Old Timer",
+        b"",
+        b"1000 +0000",
+        ["missing email"],
+    )
+    assert normalize(b"", None, None) == (
+        b"Unknown",
+        b"",
+        b"0 +0000",
+        ["missing email", "invalid date ''"],
+    )
+    assert normalize(b"A", b"a@x", b"yesterday") == (
+        b"A",
+        b"a@x",
+        b"0 +0000",
+        ["invalid date 'yesterday'"],
+    )
+
+
+def test_message_rewrites():
+    issue_link = git_toprepo.parse_message_rewrite(r"s/(^|\s)#(\d+)/\1sub#\2/g")
+    assert git_toprepo.apply_message_rewrites(
+        b"Fix #12\n\nSee #3 and x#4.\n", [issue_link]
+    ) == (b"Fix sub#12\n\nSee sub#3 and x#4.\n")
+
+    # Once per line without the g flag, any delimiter can be used.
+    path = git_toprepo.parse_message_rewrite(r"s|src/|sub/src/|")
+    assert git_toprepo.apply_message_rewrites(
+        b"src/a.c src/b.c\nsrc/c.c\n", [path]
+    ) == (b"sub/src/a.c src/b.c\nsub/src/c.c\n")
+    escaped = git_toprepo.parse_message_rewrite(r"s/a\/b/c/")
+    assert git_toprepo.apply_message_rewrites(b"a/b", [escaped]) == b"c"
+
+    for bad in ["", "x/a/b/", "s/a/b", "s/a/b/x", "s/(/b/"]:
+        with pytest.raises(ValueError):
+            git_toprepo.parse_message_rewrite(bad)
+
+    repo_config_dict = git_toprepo.ConfigDict()
+    repo_config_dict["urls"] = ["../subrepo"]
+    repo_config_dict["messagerewrite"] = ["s/#/sub#/g"]
+    repo_config = parse_sub_repo_config(repo_config_dict)
+    assert len(repo_config.message_rewrites) == 1
+    assert repo_config.push_message_rewrites == []
+    repo_config_dict["pushmessagerewrite"] = ["s/sub#"]
+    with pytest.raises(git_toprepo.ConfigParsingError, match="pushMessageRewrite"):
+        parse_sub_repo_config(repo_config_dict)
+
+
+def test_trim_submodule_updates():
+    bump_only = b"""\
+Update git submodules
+
+* Update sub from branch 'main'
+  to 0123abc
+  - Fix the thing
+
+    With a body.
+
+* Update other/sub from branch 'main'
+  to 4567def
+  - Update git submodules
+
+    * Update nested from branch 'main'
+      to 89abcde
+"""
+    assert git_toprepo.trim_submodule_updates(bump_only) == b"Update git submodules\n"
+
+    with_footer = b"""\
+Feature X
+
+Body text
+* submodules:
+* Update sub from branch 'main'
+  to 0123abc
+  - Fix the thing
+
+Change-Id: I0123
+"""
+    assert git_toprepo.trim_submodule_updates(with_footer) == (
+        b"Feature X\n\nBody text\n\nChange-Id: I0123\n"
+    )
+    assert git_toprepo.trim_submodule_updates(b"Plain\n\nBody\n") == (
+        b"Plain\n\nBody\n"
+    )
+
+
+def test_parse_annotations():
+    message = b"""\
+Subject
+
+^-- <top> 0123abc
+^-- sub/dir 4567def\r
+Not ^-- an annotation 89ab
+"""
+    assert git_toprepo.parse_annotations(message) == [
+        (b"<top>", b"0123abc"),
+        (b"sub/dir", b"4567def"),
+    ]
+
+
+def test_format_graphviz():
+    dot = git_toprepo.format_graphviz(
+        [
+            (b"m2", [b"m1", b"m0"], b'Merge "x"\n\n^-- <top> a2\n'),
+            (b"m1", [], b"Root\n\n^-- sub b1\n"),
+        ]
+    )
+    assert dot == (
+        "digraph toprepo {\n"
+        + "  rankdir=BT;\n"
+        + '  "m2" [label="m2 Merge \\"x\\""];\n'
+        + '  "m2" -> "m1";\n'
+        + '  "m2" -> "m0" [style=dashed];\n'
+        + '  "<top> a2" [shape=box, label="<top> a2"];\n'
+        + '  "m2" -> "<top> a2" [style=dotted];\n'
+        + '  "m1" [label="m1 Root"];\n'
+        + '  "sub b1" [shape=box, label="sub b1"];\n'
+        + '  "m1" -> "sub b1" [style=dotted];\n'
+        + "}\n"
+    )
+
+
+def test_format_original_log():
+    commits = [
+        (b"m2", b"Subject\n\nBody\n\n^-- <top> a2\n^-- sub b2\n"),
+        (b"m1", b"Root\n"),
+    ]
+    assert git_toprepo.format_original_log(commits, False) == "m2 Subject\nm1 Root\n"
+    assert git_toprepo.format_original_log(commits, True) == (
+        "m2 Subject\n" + "    <top> a2\n" + "    sub b2\n" + "m1 Root\n"
+    )
+
+
+def test_format_notes_import():
+    assert git_toprepo.format_notes_import(
+        "refs/notes/review",
+        {b"b" * 40: [b"Reviewed\n", b"Verified"], b"a" * 40: [b"Other\n"]},
+    ) == b"".join(
+        [
+            b"commit refs/notes/review\n",
+            b"committer git-toprepo <> 0 +0000\n",
+            b"data 58\nNotes translated from the sub repositories by git-toprepo\n",
+            b"N inline %s\ndata 6\nOther\n" % (b"a" * 40),
+            b"N inline %s\ndata 19\nReviewed\n\nVerified\n" % (b"b" * 40),
+            b"\n",
+        ]
+    )
+
+
+def test_get_prunable_repos():
+    repo_configs = [
+        make_repo_config("kept"),
+        make_repo_config("disabled", enabled=False),
+    ]
+    present = ["top", "kept", "disabled", "removed"]
+    assert git_toprepo.get_prunable_repos(present, repo_configs) == [
+        "disabled",
+        "removed",
+    ]
+
+
+def test_format_notices():
+    entries = [
+        (
+            PurePosixPath("third_party/foo"),
+            make_repo_config(
+                "foo", license="MIT", provenance_url="https://github.com/foo/foo"
+            ),
+            b"0123abc",
+        ),
+        (PurePosixPath("bar"), make_repo_config("bar"), None),
+        # Another repository with the same URL is not listed again.
+        (PurePosixPath("bar"), make_repo_config("bar-mirror"), None),
+    ]
+    assert git_toprepo.format_notices("fedcba9", entries) == """\
+# Third-party notices
+
+Sub repositories included in monorepo commit fedcba9.
+
+## foo
+
+* Path: `third_party/foo`
+* Source: https://github.com/foo/foo
+* Commit: 0123abc
+* License: MIT
+
+## bar
+
+* Path: `bar`
+* Source: ssh://server/bar
+* License: unknown
+"""
+
+
+def test_summarize_url_references():
+    history = [
+        (b"c3", {"../a", "../b"}),
+        (b"c2", {"../a"}),
+        (b"c1", {"../a", "../old"}),
+    ]
+    assert git_toprepo.summarize_url_references(history) == {
+        "../a": (b"c1", b"c3"),
+        "../b": (b"c3", b"c3"),
+        "../old": (b"c1", b"c1"),
+    }
 
 
 def test_join_submodule_url():
@@ -102,6 +684,75 @@ def test_join_submodule_url():
     assert git_toprepo.join_submodule_url("/data/repo", "../other") == "/data/other"
 
 
+def test_split_url_location():
+    for url, expected in [
+        ("ssh://user@host:29418/project", ("ssh://user@host:29418/", "project")),
+        ("https://host", ("https://host/", "")),
+        ("file:///data/repo", ("file:///", "data/repo")),
+        ("user@host:org/repo", ("user@host:", "org/repo")),
+        ("user@[::1]:repo", ("user@[::1]:", "repo")),
+        ("ext::ssh://host/repo", ("ext::ssh://host/", "repo")),
+        ("C:/data/repo", ("", "C:/data/repo")),
+        ("/data/repo", ("/", "data/repo")),
+        ("../repo", ("", "../repo")),
+    ]:
+        assert git_toprepo.split_url_location(url) == expected, url
+
+
+def test_join_submodule_url_with_user_and_port():
+    for parent, other, expected in [
+        (
+            "ssh://user@host:29418/project",
+            "../other",
+            "ssh://user@host:29418/other",
+        ),
+        (
+            "ssh://user@host:29418/project/../sub",
+            "./other",
+            "ssh://user@host:29418/sub/other",
+        ),
+        (
+            "ssh://user@host:29418/project",
+            "../../other",
+            "ssh://user@host:29418/../other",
+        ),
+        ("ssh://host:29418", "./other", "ssh://host:29418/other"),
+        ("user@host:org/repo", "../other", "user@host:org/other"),
+        ("user@host:repo", "../other", "user@host:other"),
+        ("user@host:repo", "../../other", "user@host:../other"),
+        ("host:repo.git", "./sub", "host:repo.git/sub"),
+        ("git@[::1]:org/repo", "../other", "git@[::1]:org/other"),
+        ("ext::ssh://host/org/repo", "../other", "ext::ssh://host/org/other"),
+    ]:
+        assert git_toprepo.join_submodule_url(parent, other) == expected, (
+            parent,
+            other,
+        )
+
+
+def test_url_rewrites():
+    config_dict = git_toprepo.ConfigDict()
+    config_dict["url.https://mirror/.insteadof"] = ["ssh://gerrit/"]
+    config_dict["toprepo.url.https://cache/org/.insteadof"] = [
+        "ssh://gerrit/org/",
+        "git@github.com:org/",
+    ]
+    config_dict["url.unrelated.pushinsteadof"] = ["ssh://gerrit/"]
+    url_rewrites = git_toprepo.parse_url_rewrites(config_dict)
+    assert sorted(url_rewrites) == [
+        ("git@github.com:org/", "https://cache/org/"),
+        ("ssh://gerrit/", "https://mirror/"),
+        ("ssh://gerrit/org/", "https://cache/org/"),
+    ]
+    for url, expected in [
+        ("ssh://gerrit/sub", "https://mirror/sub"),
+        ("ssh://gerrit/org/sub", "https://cache/org/sub"),
+        ("git@github.com:org/sub", "https://cache/org/sub"),
+        ("ssh://other/sub", "ssh://other/sub"),
+    ]:
+        assert git_toprepo.rewrite_url(url, url_rewrites) == expected, url
+
+
 def test_config_repo_is_wanted():
     assert git_toprepo.Config.repo_is_wanted("Repo", ["+Repo"])
     assert not git_toprepo.Config.repo_is_wanted("Repo", ["+Repo", "-Repo"])
@@ -111,6 +762,234 @@ def test_config_repo_is_wanted():
     assert not git_toprepo.Config.repo_is_wanted("Repo", ["+.*", "-Repo"])
 
 
+def test_parse_repo_config_git_config():
+    repo_config_dict = git_toprepo.ConfigDict()
+    repo_config_dict["urls"] = ["../subrepo"]
+    repo_config_dict["gitconfig"] = [
+        "protocol.version=0",
+        "core.sshCommand=ssh -o IdentitiesOnly=yes",
+    ]
+    repo_config = parse_sub_repo_config(repo_config_dict)
+    assert repo_config.git_config_args() == [
+        "-c",
+        "protocol.version=0",
+        "-c",
+        "core.sshCommand=ssh -o IdentitiesOnly=yes",
+    ]
+
+    repo_config_dict["gitconfig"] = ["protocol.version"]
+    with pytest.raises(git_toprepo.ConfigParsingError, match="<key>=<value>"):
+        parse_sub_repo_config(repo_config_dict)
+
+
+def test_parse_repo_config_push_url():
+    # Fetch from a mirror, push to the origin server.
+    repo_config_dict = git_toprepo.ConfigDict()
+    repo_config_dict["urls"] = ["../subrepo"]
+    repo_config = parse_sub_repo_config(
+        repo_config_dict, "https://mirror/top", "ssh://user@gerrit:29418/top"
+    )
+    assert repo_config.fetch_url == "https://mirror/subrepo"
+    assert repo_config.push_url == "ssh://user@gerrit:29418/subrepo"
+
+    repo_config_dict["fetchurl"] = ["https://other-mirror/subrepo"]
+    repo_config_dict["pushurl"] = ["../pushed"]
+    repo_config = parse_sub_repo_config(
+        repo_config_dict, "https://mirror/top", "ssh://user@gerrit:29418/top"
+    )
+    assert repo_config.fetch_url == "https://other-mirror/subrepo"
+    assert repo_config.push_url == "ssh://user@gerrit:29418/pushed"
+
+
+def test_parse_fetch_refspec():
+    assert git_toprepo.parse_fetch_refspec("main") == (False, "main", None)
+    assert git_toprepo.parse_fetch_refspec("+refs/heads/*:refs/remotes/m/*") == (
+        True,
+        "refs/heads/*",
+        "refs/remotes/m/*",
+    )
+    with pytest.raises(ValueError, match="Both or neither"):
+        git_toprepo.parse_fetch_refspec("refs/heads/*:refs/heads/main")
+    with pytest.raises(ValueError, match="single '\\*'"):
+        git_toprepo.parse_fetch_refspec("refs/*/*")
+
+
+def test_is_toprepo_managed_ref():
+    prefix = git_toprepo.default_fetch_ref_prefix
+    assert git_toprepo.is_toprepo_managed_ref("refs/remotes/origin/main", prefix)
+    assert git_toprepo.is_toprepo_managed_ref("refs/repos/top/heads/main", prefix)
+    assert git_toprepo.is_toprepo_managed_ref("refs/toprepo/config/x", prefix)
+    assert git_toprepo.is_toprepo_managed_ref("refs/fetched/1", "refs/fetched/")
+    assert not git_toprepo.is_toprepo_managed_ref("refs/heads/main", prefix)
+    assert not git_toprepo.is_toprepo_managed_ref("refs/remotes/mirror/main", prefix)
+
+
+def test_expand_glob_refspec():
+    remote_refs = [
+        "refs/heads/main",
+        "refs/heads/feature/a",
+        "refs/tags/v1",
+        "refs/heads/release",
+    ]
+    assert git_toprepo.expand_glob_refspec(
+        "refs/heads/*", "refs/remotes/mirror/*", remote_refs
+    ) == [
+        ("refs/heads/feature/a", "refs/remotes/mirror/feature/a"),
+        ("refs/heads/main", "refs/remotes/mirror/main"),
+        ("refs/heads/release", "refs/remotes/mirror/release"),
+    ]
+    assert git_toprepo.expand_glob_refspec("refs/heads/re*se", None, remote_refs) == [
+        ("refs/heads/release", None)
+    ]
+    assert git_toprepo.expand_glob_refspec("refs/heads/main", None, []) == [
+        ("refs/heads/main", None)
+    ]
+
+
+def test_with_fetch_tags_policy():
+    assert git_toprepo.with_fetch_tags_policy(
+        git_toprepo.default_fetch_args, "all"
+    ) == ["--prune", "--prune-tags", "--tags"]
+    assert git_toprepo.with_fetch_tags_policy(
+        git_toprepo.default_fetch_args, "none"
+    ) == ["--prune", "--no-tags"]
+    assert git_toprepo.with_fetch_tags_policy(
+        ["--depth=1", "--tags"], "reachable"
+    ) == ["--depth=1"]
+
+
+def test_parse_config_bool():
+    for value in ["1", "true", "Yes", "on"]:
+        assert git_toprepo.parse_config_bool("a.b", value) is True
+    for value in ["0", "False", "no", "off"]:
+        assert git_toprepo.parse_config_bool("a.b", value) is False
+    with pytest.raises(git_toprepo.ConfigParsingError, match="Invalid a.b 'maybe'"):
+        git_toprepo.parse_config_bool("a.b", "maybe")
+
+
+def test_without_fetch_prune():
+    assert git_toprepo.without_fetch_prune(git_toprepo.default_fetch_args) == [
+        "--tags"
+    ]
+    assert git_toprepo.without_fetch_prune(["-p", "-P", "--depth=1"]) == [
+        "--depth=1"
+    ]
+
+    repo_config_dict = git_toprepo.ConfigDict()
+    repo_config_dict["urls"] = ["../subrepo"]
+    repo_config_dict["prune"] = ["false"]
+    repo_config = parse_sub_repo_config(repo_config_dict)
+    assert repo_config.fetch_args == ["--tags"]
+
+    repo_config_dict["prune"] = ["sometimes"]
+    with pytest.raises(git_toprepo.ConfigParsingError, match="prune"):
+        parse_sub_repo_config(repo_config_dict)
+
+
+def test_is_fetch_prune_error():
+    assert git_toprepo.is_fetch_prune_error("fatal: pruning not allowed\n")
+    assert git_toprepo.is_fetch_prune_error("fatal: invalid refspec '+refs/*'\n")
+    assert not git_toprepo.is_fetch_prune_error(
+        "fatal: Could not read from remote repository.\n"
+    )
+
+
+def test_parse_repo_config_long_name():
+    repo_config_dict = git_toprepo.ConfigDict()
+    repo_config_dict["urls"] = ["../subrepo"]
+    long_name = "x" * (git_toprepo.max_repo_name_length + 1)
+    with pytest.raises(git_toprepo.ConfigParsingError, match="use a shorter name"):
+        git_toprepo.Config.parse_repo_config(
+            long_name,
+            repo_config_dict,
+            ["+.*"],
+            parent_fetch_url="ssh://server/top",
+            parent_push_url="ssh://server/top",
+        )
+
+
+def test_get_mapped_tag_updates():
+    existing_tags = {
+        "refs/tags/lib/v1": "1" * 40,
+        "refs/tags/lib/v2": "2" * 40,
+        "refs/tags/lib/top-tag": "3" * 40,
+        "refs/tags/lib/moved": "4" * 40,
+    }
+    mapped_tags = {
+        "refs/tags/lib/v1": "1" * 40,
+        "refs/tags/lib/v2": "2" * 40,
+        "refs/tags/lib/moved": "5" * 40,
+    }
+    new_tags = {
+        "refs/tags/lib/v1": "1" * 40,
+        "refs/tags/lib/v3": "6" * 40,
+        "refs/tags/lib/top-tag": "7" * 40,
+    }
+    instructions, skipped = git_toprepo.get_mapped_tag_updates(
+        existing_tags, mapped_tags, new_tags
+    )
+    # The tag of the top repository and the moved tag are left alone.
+    assert instructions.splitlines() == [
+        "delete refs/toprepo/mapped-tags/lib/moved",
+        "delete refs/tags/lib/v2",
+        "delete refs/toprepo/mapped-tags/lib/v2",
+        f"update refs/tags/lib/v3 {'6' * 40}",
+        f"update refs/toprepo/mapped-tags/lib/v3 {'6' * 40}",
+    ]
+    assert skipped == ["refs/tags/lib/top-tag"]
+
+
+def test_warning_log(tmp_path, capsys):
+    log = git_toprepo.WarningLog(tmp_path / "warnings.log")
+    log.max_printed_per_key = 2
+    for i in range(5):
+        log.warn(("invalid .gitmodules", "sub"), f"Invalid at commit {i}")
+    # Visited again by a later pass.
+    log.warn(("invalid .gitmodules", "sub"), "Invalid at commit 0")
+    log.warn(("invalid .gitmodules", "other"), "Invalid other")
+    log.flush()
+    out = capsys.readouterr().out
+    assert "Invalid at commit 1" in out
+    assert "Invalid at commit 2" not in out
+    assert "Invalid other" in out
+    assert "3 more invalid .gitmodules warnings for sub were not printed" in out
+    assert "All 6 warnings are listed in" in out
+    lines = (tmp_path / "warnings.log").read_text().splitlines()
+    assert lines[4] == "WARNING: Invalid at commit 4"
+    assert len(lines) == 6
+    # Flushing resets the counts.
+    log.warn(("invalid .gitmodules", "sub"), "Invalid again")
+    assert "Invalid again" in capsys.readouterr().out
+
+
+def test_format_shell_prompt(tmp_path):
+    now = time.time()
+    git_dir = tmp_path / ".git"
+    assert git_toprepo.format_shell_prompt(None, now) == ""
+    git_dir.mkdir()
+    assert git_toprepo.find_git_dir_without_git(tmp_path / "sub" / "dir") == git_dir
+    assert git_toprepo.format_shell_prompt(git_dir, now) == ""
+
+    (git_dir / "repos" / "top").mkdir(parents=True)
+    assert git_toprepo.format_shell_prompt(git_dir, now) == "toprepo stale"
+    (git_dir / "toprepo").mkdir()
+    last_time = time.strftime("%Y-%m-%dT%H:%M:%S", time.localtime(now - 60))
+    (git_dir / "toprepo" / "metrics.json").write_text(
+        json.dumps([{"time": last_time, "refs": [], "seconds": {}}])
+    )
+    (git_dir / "toprepo" / "pending-filter.json").write_text(json.dumps([{}, {}]))
+    assert git_toprepo.format_shell_prompt(git_dir, now) == "toprepo pending:2"
+
+    # A worktree refers to the common git directory.
+    worktree = tmp_path / "worktree"
+    (git_dir / "worktrees" / "wt").mkdir(parents=True)
+    (git_dir / "worktrees" / "wt" / "commondir").write_text("../..\n")
+    worktree.mkdir()
+    (worktree / ".git").write_text(f"gitdir: {git_dir / 'worktrees' / 'wt'}\n")
+    found = git_toprepo.find_git_dir_without_git(worktree)
+    assert found is not None and found.resolve() == git_dir.resolve()
+
+
 def test_annotate_message():
     # Don't fold the footer into the subject line, leave an empty line.
     assert (
@@ -228,6 +1107,18 @@ message
         is None
     )
 
+    # Paths are not regexes.
+    assert (
+        git_toprepo.try_parse_commit_hash_from_message(example_message, b"sub.dir")
+        is None
+    )
+    assert (
+        git_toprepo.try_parse_commit_hash_from_message(
+            b"Subject\r\n^-- lib/c++ 123abc \r\n", b"lib/c++"
+        )
+        == b"123abc"
+    )
+
 
 def test_try_get_topic_from_message():
     example_message = b"""\
@@ -335,10 +1226,88 @@ def test_remote_to_repo():
     assert git_toprepo.remote_to_repo("no/subrepo", git_modules, config) is None
 
 
+def test_get_submodule_containing():
+    def module(path: str) -> git_toprepo.GitModuleInfo:
+        return git_toprepo.GitModuleInfo(
+            name=path,
+            path=PurePosixPath(path),
+            branch=None,
+            url=f"ssh://github.com/org/{path}",
+            raw_url=f"../{path}",
+        )
+
+    outer = module("third_party/foo")
+    inner = module("third_party/foo/nested")
+    git_modules = [inner, outer, module("third_party/foobar")]
+    get = git_toprepo.get_submodule_containing
+    assert get(git_modules, PurePosixPath("third_party/foo")) == outer
+    assert get(git_modules, PurePosixPath("third_party/foo/src/a")) == outer
+    assert get(git_modules, PurePosixPath("third_party/foo/nested/x")) == inner
+    assert get(git_modules, PurePosixPath("third_party")) is None
+    assert get(git_modules, PurePosixPath("third_party/foob")) is None
+
+
+def test_gitmodules_conflicts(capsys):
+    loader = git_toprepo.StaticContentConfigLoader(
+        """\
+[submodule "foo"]
+    path = foo
+    url = ../foo-old
+[submodule "foo"]
+    url = ../foo-new
+[submodule "bar"]
+    path = bar
+    url = ../bar
+"""
+    )
+
+    def get(policy: str):
+        modules = git_toprepo.get_gitmodules_info(
+            loader, "https://host/top", policy, " at commit abc"
+        )
+        return {str(mod.path): mod.raw_url for mod in modules}
+
+    with pytest.raises(ValueError, match="toprepo.gitmodulesConflicts"):
+        get("error")
+    assert get("last-wins") == {"foo": "../foo-new", "bar": "../bar"}
+    assert get("first-wins") == {"foo": "../foo-old", "bar": "../bar"}
+    assert get("skip-path") == {"bar": "../bar"}
+    assert "submodule.foo.url in .gitmodules at commit abc" in capsys.readouterr().out
+
+
+def test_gitmodules_sloppy_paths():
+    loader = git_toprepo.StaticContentConfigLoader(
+        """\
+[submodule "foo"]
+    path = ./Modules/Foo/
+    url = ../foo
+[submodule "bar"]
+    path = Modules\\\\Bar
+    url = ../bar
+[submodule "baz"]
+    path = /baz//qux
+    url = ../baz
+"""
+    )
+    modules = git_toprepo.get_gitmodules_info(loader, "https://host/top")
+    assert sorted(str(mod.path) for mod in modules) == [
+        "Modules/Bar",
+        "Modules/Foo",
+        "baz/qux",
+    ]
+    for bad in ["", ".", "./", "../outside", "a/../b"]:
+        with pytest.raises(ValueError, match="Invalid submodule path"):
+            git_toprepo.normalize_gitmodules_path(bad)
+
+
 def commit_env(seed: str = ""):
-    """With this env, commits become deterministic."""
+    """With this env, commits become deterministic.
+
+    The rest of the environment is kept, e.g. PATH to find git.
+    """
     name_suffix = str(hash(seed))
     return {
+        **os.environ,
         "GIT_AUTHOR_NAME": f"A Name {name_suffix}",
         "GIT_AUTHOR_EMAIL": "a@no.domain",
         "GIT_AUTHOR_DATE": "2023-01-02T03:04:05Z+01:00",
@@ -481,6 +1450,41 @@ def test_get_config_location(tmp_path):
     ]
 
 
+def test_set_repo_wanted(tmp_path):
+    worktree_path = tmp_path / "worktree"
+    worktree_path.mkdir(parents=True)
+    subprocess.check_call(cwd=worktree_path, args="git init --quiet".split(" "))
+    for key, value in [
+        ("remote.origin.url", "https://server/top"),
+        ("remote.top.pushUrl", "https://server/top"),
+        ("toprepo.repo.sub.urls", "../sub"),
+    ]:
+        subprocess.check_call(cwd=worktree_path, args=["git", "config", key, value])
+    monorepo = git_toprepo.MonoRepo(worktree_path)
+
+    def load_config() -> git_toprepo.Config:
+        config_loader = git_toprepo.LocalGitConfigLoader(monorepo)
+        return git_toprepo.Config.create(config_loader.get_config_dict())
+
+    def set_wanted(name: str, wanted: bool) -> int:
+        config_dict = git_toprepo.LocalGitConfigLoader(monorepo).get_config_dict()
+        return git_toprepo.set_repo_wanted(monorepo, config_dict, name, wanted)
+
+    assert load_config().repos[0].enabled
+    assert set_wanted("sub", False) == 0
+    assert not load_config().repos[0].enabled
+    assert set_wanted("sub", False) == 0
+    assert set_wanted("sub", True) == 0
+    assert load_config().repos[0].enabled
+    assert set_wanted("other", True) == 1
+    repos_config = subprocess.check_output(
+        cwd=worktree_path,
+        args=["git", "config", "--get-all", "toprepo.role.default.repos"],
+        text=True,
+    )
+    assert repos_config == "+.*\n-sub\n+sub\n"
+
+
 def test_read_config_from_git(tmp_path):
     """Test the LocalGitConfigLoader."""
     worktree_path = tmp_path / "worktree"
@@ -499,6 +1503,119 @@ def test_read_config_from_git(tmp_path):
     assert config_dict["toprepo.missing-commits.rev-test-hash"] == ["local-config"]
 
 
+def test_get_object_types(tmp_path):
+    subprocess.check_call(cwd=tmp_path, args="git init --quiet --bare".split(" "))
+    blob_hash = subprocess.check_output(
+        cwd=tmp_path, args="git hash-object -w --stdin".split(" "), input=b"x\n"
+    ).strip()
+    missing_hash = b"0123456789abcdef0123456789abcdef01234567"
+    repo = git_toprepo.Repo(tmp_path)
+    assert git_toprepo.get_object_types(repo, [missing_hash, blob_hash]) == {
+        blob_hash: "blob",
+        missing_hash: "missing",
+    }
+    assert git_toprepo.get_object_types(repo, []) == {}
+
+
+def test_verify_mono_commits(tmp_path):
+    def git(*args: str, stdin: str = "") -> str:
+        return subprocess.check_output(
+            ["git"] + list(args), cwd=tmp_path, env=commit_env(), input=stdin, text=True
+        ).strip()
+
+    def commit(tree: str, message: str) -> str:
+        return git("commit-tree", "-m", message, tree)
+
+    git("init", "--quiet")
+    blob_a = git("hash-object", "-w", "--stdin", stdin="a\n")
+    blob_b = git("hash-object", "-w", "--stdin", stdin="b\n")
+    inner_commit = commit(git("mktree", stdin=f"100644 blob {blob_b}\tb\n"), "Inner")
+    sub_commit = commit(
+        git(
+            "mktree",
+            stdin=f"100644 blob {blob_a}\ta\n160000 commit {inner_commit}\tinner\n",
+        ),
+        "Sub",
+    )
+    top_commit = commit(
+        git("mktree", stdin=f"160000 commit {sub_commit}\tsub\n"), "Top"
+    )
+    # The nested submodule is expanded in the monorepo.
+    inner_tree = git("mktree", stdin=f"100644 blob {blob_b}\tb\n")
+    expanded_sub_tree = git(
+        "mktree", stdin=f"100644 blob {blob_a}\ta\n040000 tree {inner_tree}\tinner\n"
+    )
+    mono_tree = git("mktree", stdin=f"040000 tree {expanded_sub_tree}\tsub\n")
+    message = f"Top\n^-- <top> {top_commit}\n\nSub\n^-- sub {sub_commit}\n"
+    mono_commit = commit(mono_tree, message)
+
+    monorepo = git_toprepo.MonoRepo(tmp_path)
+    assert git_toprepo.verify_mono_commits(monorepo, [mono_commit]) == []
+
+    broken_sub_tree = git("mktree", stdin=f"100644 blob {blob_b}\ta\n")
+    broken_mono_tree = git("mktree", stdin=f"040000 tree {broken_sub_tree}\tsub\n")
+    broken_commit = commit(broken_mono_tree, message.replace(sub_commit, inner_commit))
+    assert git_toprepo.verify_mono_commits(monorepo, [broken_commit]) == [
+        f"{broken_commit}: The content of sub differs from commit {inner_commit}",
+        f"{broken_commit}: Top commit {top_commit} refers to {sub_commit} at sub, "
+        + f"not {inner_commit}",
+    ]
+
+
+def test_update_fetch_head(tmp_path):
+    def git(*args: str) -> str:
+        return subprocess.check_output(
+            ["git"] + list(args), cwd=tmp_path, env=commit_env(), input="", text=True
+        ).strip()
+
+    git("init", "--quiet")
+    tree = git("mktree")
+    first = git("commit-tree", "-m", "First", tree)
+    second = git("commit-tree", "-m", "Second", tree)
+    git("update-ref", "refs/a", first)
+    git("update-ref", "refs/b", second)
+
+    monorepo = git_toprepo.MonoRepo(tmp_path)
+    git_toprepo.update_fetch_head(monorepo, [("refs/a", "a"), ("refs/b", "b")])
+    assert (tmp_path / ".git/FETCH_HEAD").read_text() == (
+        f"{first}\t\ta\n{second}\tnot-for-merge\tb\n"
+    )
+    # Every entry is in the reflog, the one to merge last.
+    assert git("reflog", "--format=%H %gs", "FETCH_HEAD").splitlines() == [
+        f"{first} toprepo fetch: a",
+        f"{second} toprepo fetch: b",
+    ]
+
+
+def test_collect_commits_with_malformed_identity(tmp_path):
+    def git(*args: str, stdin: str = "") -> str:
+        return subprocess.check_output(
+            ["git"] + list(args), cwd=tmp_path, env=commit_env(), input=stdin, text=True
+        ).strip()
+
+    git("init", "--quiet")
+    tree = git("mktree")
+    commit_hash = git(
+        "hash-object",
+        "-t",
+        "commit",
+        "-w",
+        "--literally",
+        "--stdin",
+        stdin=f"tree {tree}\nauthor Ancient 1000000000 +0000\n"
+        + "committer C <c@x> 1000000000 +0000\n\nOld\n",
+    )
+    git("update-ref", "refs/heads/main", commit_hash)
+
+    commit_map = git_toprepo.CommitMap.collect_commits(
+        git_toprepo.MonoRepo(tmp_path), ["--all"]
+    )
+    commit = commit_map.hash_to_commit[commit_hash.encode("utf-8")]
+    assert commit.author_name == b"Ancient"
+    assert commit.author_email == b""
+    assert commit.author_date == b"1000000000 +0000"
+
+
 def test_read_config_from_disk(tmp_path):
     """Test the LocalFileConfigLoader."""
     config_path = tmp_path / "config"
@@ -541,6 +1658,63 @@ def test_read_config_casing(tmp_path):
     }
 
 
+def test_read_config_include_and_env(tmp_path):
+    """Test the ExpandingConfigLoader."""
+    (tmp_path / "sub").mkdir()
+    (tmp_path / "config").write_bytes(
+        b"""\
+[toprepo.repo "a"]
+    urls = https://${SITE}/a
+[include]
+    path = sub/${SITE_NAME}.config
+[toprepo.repo "c"]
+    urls = literal$$
+"""
+    )
+    (tmp_path / "sub" / "stockholm.config").write_bytes(
+        b"""\
+[toprepo.repo "b"]
+    urls = https://${SITE}/b
+"""
+    )
+
+    config_loader = git_toprepo.ExpandingConfigLoader(
+        git_toprepo.LocalFileConfigLoader(tmp_path / "config"),
+        {"SITE": "mirror.example", "SITE_NAME": "stockholm"},
+    )
+    assert config_loader.get_config_dict() == {
+        "toprepo.repo.a.urls": ["https://mirror.example/a"],
+        "toprepo.repo.b.urls": ["https://mirror.example/b"],
+        "toprepo.repo.c.urls": ["literal$"],
+    }
+
+    config_loader.environ = {"SITE_NAME": "stockholm"}
+    with pytest.raises(ValueError, match="SITE is not set"):
+        config_loader.get_config_dict()
+
+
+def test_read_config_directory(tmp_path):
+    config_dir = tmp_path / "toprepo"
+    config_dir.mkdir()
+    (config_dir / "b.config").write_bytes(b"[toprepo]\n    key = b\n")
+    (config_dir / "a.config").write_bytes(b"[toprepo]\n    key = a\n")
+    (config_dir / "ignored.txt").write_bytes(b"[toprepo]\n    key = c\n")
+
+    config_loader = git_toprepo.ExpandingConfigLoader(
+        git_toprepo.LocalFileConfigLoader(config_dir), {}
+    )
+    assert config_loader.get_config_dict() == {"toprepo.key": ["a", "b"]}
+
+
+def test_substitute_env_vars():
+    assert git_toprepo.substitute_env_vars("a${X}b", {"X": "1"}) == "a1b"
+    assert git_toprepo.substitute_env_vars("$HOME $$", {}) == "$HOME $"
+    with pytest.raises(ValueError, match="Invalid"):
+        git_toprepo.substitute_env_vars("${X", {"X": "1"})
+    with pytest.raises(ValueError, match="Invalid"):
+        git_toprepo.substitute_env_vars("${1X}", {"1X": "1"})
+
+
 def test_get_config(tmp_path, capsys):
     example = GitTopRepoExample(tmp_path)
     server_top = example.init_server_top()
@@ -598,6 +1772,63 @@ toprepo.missing-commits.rev-test-hash=local-override-path
     )
 
 
+def test_expansion_order_is_by_path(tmp_path):
+    """Submodules bumped together are expanded in path order, reproducibly."""
+
+    def git(cwd: Path, *args: str) -> str:
+        return subprocess.check_output(
+            ["git", "-c", "protocol.file.allow=always"] + list(args),
+            cwd=cwd,
+            env=commit_env(),
+            text=True,
+        ).strip()
+
+    server = tmp_path / "server"
+    for name in ["sub", "sub2", "top"]:
+        (server / name).mkdir(parents=True)
+        git(server / name, "init", "--quiet")
+        git(server / name, "commit", "--quiet", "--allow-empty", "-m", name)
+    for name in ["sub", "sub2"]:
+        git(server / "top", "submodule", "add", "--quiet", f"../{name}", name)
+    git(server / "top", "commit", "--quiet", "-m", "Add submodules")
+    for name in ["sub", "sub2"]:
+        git(server / name, "commit", "--quiet", "--allow-empty", "-m", "Bump")
+        git(server / "top" / name, "pull", "--quiet")
+    git(server / "top", "commit", "--quiet", "-am", "Bump both")
+    (tmp_path / "toprepo.config").write_text(
+        '[toprepo.repo "sub"]\n\turls = ../sub\n'
+        + '[toprepo.repo "sub2"]\n\turls = ../sub2\n'
+    )
+    config_blob = git(server / "top", "hash-object", "-w", "../../toprepo.config")
+    config_tree = subprocess.check_output(
+        ["git", "mktree"],
+        cwd=server / "top",
+        input=f"100644 blob {config_blob}\ttoprepo.config\n",
+        text=True,
+    ).strip()
+    config_commit = git(server / "top", "commit-tree", "-m", "Config", config_tree)
+    git(server / "top", "update-ref", "refs/meta/git-toprepo", config_commit)
+
+    mono = tmp_path / "mono"
+    assert (
+        git_toprepo.main(
+            ["argv0", "-C", str(tmp_path), "init", str(server / "top"), str(mono)]
+        )
+        == 0
+    )
+    git(mono, "config", "protocol.file.allow", "always")
+    assert git_toprepo.main(["argv0", "-C", str(mono), "fetch"]) == 0
+    message = git(mono, "log", "-1", "--format=%B", "refs/remotes/origin/master")
+    subdirs = [
+        subdir for subdir, _ in git_toprepo.parse_annotations(message.encode())
+    ]
+    assert subdirs == [b"<top>", b"sub", b"sub2"]
+
+    refs = git(mono, "for-each-ref", "refs/remotes/origin/")
+    assert git_toprepo.main(["argv0", "-C", str(mono), "refilter"]) == 0
+    assert git(mono, "for-each-ref", "refs/remotes/origin/") == refs
+
+
 def test_init_fetch_checkout():
     pass
 